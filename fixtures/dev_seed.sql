@@ -0,0 +1,6 @@
+-- Development hub and points, for `ahub mock seed` and the tests. Real hubs are provisioned
+-- with their own id and token by `ahub token --hub-id <id> --set <token>`.
+insert into AccessHub (id, api_token) values ('cl2uwi6uv0030ybthbkls5w0i',
+    'd627713660c1891414ac55a6ccd1c1294292bb19a9e6be741f340782a531e331');
+
+insert into AccessPoint (id, position) values (1, 1), (2, 2), (3, 3), (4, 4);
@@ -0,0 +1,25 @@
+create table Outbox (
+    id integer not null primary key,
+    kind text not null,
+    record_id integer,
+    payload text,
+    created_at datetime not null default current_timestamp,
+    attempts integer not null default 0,
+    last_attempt_at datetime,
+    last_error text,
+    delivered_at datetime
+);
+
+create index Outbox_kind_delivered_at_index on Outbox(kind, delivered_at);
+
+-- Every access event is queued for upload regardless of which command inserted it.
+create trigger AccessEvent_outbox after insert on AccessEvent
+begin
+    insert into Outbox (kind, record_id, created_at) values ('event', new.id, new.at);
+end;
+
+-- Events at or before the cloud cursor were already uploaded.
+insert into Outbox (kind, record_id, created_at, delivered_at)
+select 'event', e.id, e.at, case when e.at <= h.cloud_last_access_event_at then current_timestamp end
+from AccessEvent e left join AccessHub h
+order by e.id asc;
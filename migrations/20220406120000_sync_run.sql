@@ -0,0 +1,13 @@
+create table SyncRun (
+    id integer not null primary key,
+    started_at datetime not null,
+    ended_at datetime,
+    http_status integer,
+    events_uploaded integer not null default 0,
+    users_created integer not null default 0,
+    users_updated integer not null default 0,
+    users_deleted integer not null default 0,
+    error text
+);
+
+create index SyncRun_started_at_index on SyncRun(started_at);
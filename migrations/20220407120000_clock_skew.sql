@@ -0,0 +1,6 @@
+alter table AccessHub add column clock_skew_seconds integer;
+alter table AccessHub add column clock_skew_exceeded boolean not null default false;
+
+alter table SyncRun add column clock_skew_seconds integer;
+
+alter table AccessEvent add column reason text;
@@ -0,0 +1,3 @@
+create index AccessEvent_at_index on AccessEvent(at);
+create index AccessEvent_access_point_id_index on AccessEvent(access_point_id);
+create index AccessEvent_access_user_id_index on AccessEvent(access_user_id);
@@ -0,0 +1,13 @@
+-- Users added on the hub while the cloud is unreachable. Heartbeat does not delete them.
+alter table AccessUser add column local boolean not null default false;
+
+create table AdminChange (
+    id integer not null primary key,
+    at datetime not null default current_timestamp,
+    actor text not null,
+    action text not null,
+    record_id integer,
+    detail text
+);
+
+create index AdminChange_at_index on AdminChange(at);
@@ -0,0 +1,24 @@
+-- Networked readers. A reader authenticates with its secret, of which the hub keeps only the
+-- SHA-256 hash, and asks for decisions at its points only. Events keep the reader id after
+-- the reader is removed, like user ids, and reader ids are never reused so they do not point
+-- at a new one.
+create table AccessReader (
+    id integer not null primary key autoincrement,
+    name text not null,
+    secret_hash text not null,
+    last_seen_at datetime
+);
+create table AccessPointToAccessReader (
+    access_point_id integer not null,
+    access_reader_id integer not null,
+    foreign key (access_point_id) references AccessPoint (id) on delete cascade on update cascade,
+    foreign key (access_reader_id) references AccessReader (id) on delete cascade on update cascade
+);
+
+create unique index AccessReader_name_key on AccessReader(name);
+create unique index AccessReader_secret_hash_key on AccessReader(secret_hash);
+create unique index AccessPointToAccessReader_unique on AccessPointToAccessReader(access_point_id, access_reader_id);
+create index AccessPointToAccessReader_access_reader_id_index on AccessPointToAccessReader(access_reader_id);
+
+alter table AccessEvent add column access_reader_id integer;
+create index AccessEvent_access_reader_id_index on AccessEvent(access_reader_id);
@@ -0,0 +1,20 @@
+-- The seed migration gave every hub the development hub and points 1-4. Remove them where
+-- they are still as seeded, so a new hub is provisioned with `ahub token --hub-id` and the
+-- development data comes from `ahub mock seed`. Points are kept once they have users,
+-- readers or events, and all of them when the hub was provisioned or has synced.
+delete from AccessPoint
+where id = position and id in (1, 2, 3, 4)
+    and exists (
+        select 1 from AccessHub
+        where id = 'cl2uwi6uv0030ybthbkls5w0i'
+            and api_token = 'd627713660c1891414ac55a6ccd1c1294292bb19a9e6be741f340782a531e331'
+            and cloud_last_access_event_at is null
+    )
+    and not exists (select 1 from AccessEvent e where e.access_point_id = AccessPoint.id)
+    and not exists (select 1 from AccessPointToAccessUser pu where pu.access_point_id = AccessPoint.id)
+    and not exists (select 1 from AccessPointToAccessReader pr where pr.access_point_id = AccessPoint.id);
+
+delete from AccessHub
+where id = 'cl2uwi6uv0030ybthbkls5w0i'
+    and api_token = 'd627713660c1891414ac55a6ccd1c1294292bb19a9e6be741f340782a531e331'
+    and cloud_last_access_event_at is null;
@@ -0,0 +1,29 @@
+-- Event ids are never reused, so pruning every event does not restart them below the
+-- cursors of `dump events --follow`, saved pages and the metrics tail.
+create table AccessEvent_new (
+    id integer not null primary key autoincrement,
+    at datetime not null,
+    access text not null,
+    code text not null,
+    access_user_id integer,
+    access_point_id integer not null,
+    reason text,
+    access_reader_id integer,
+    constraint AccessEvent_access_point_id_fkey foreign key (access_point_id) references AccessPoint (id) on delete restrict on update cascade
+);
+
+-- Copying the rows also starts the sequence at the newest id.
+insert into AccessEvent_new (id, at, access, code, access_user_id, access_point_id, reason, access_reader_id)
+select id, at, access, code, access_user_id, access_point_id, reason, access_reader_id from AccessEvent;
+drop table AccessEvent;
+alter table AccessEvent_new rename to AccessEvent;
+
+create index AccessEvent_at_index on AccessEvent(at);
+create index AccessEvent_access_point_id_index on AccessEvent(access_point_id);
+create index AccessEvent_access_user_id_index on AccessEvent(access_user_id);
+create index AccessEvent_access_reader_id_index on AccessEvent(access_reader_id);
+
+create trigger AccessEvent_outbox after insert on AccessEvent
+begin
+    insert into Outbox (kind, record_id, created_at) values ('event', new.id, new.at);
+end;
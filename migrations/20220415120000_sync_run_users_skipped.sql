@@ -0,0 +1,2 @@
+-- Cloud users left out of a sync because their id or code belongs to a hub-local user.
+alter table SyncRun add column users_skipped integer not null default 0;
@@ -1,14 +1,75 @@
-use sqlx::{Connection, SqliteConnection};
-
+use crate::db;
 use crate::domain::{ActiveCode, Point};
+use crate::metrics;
+use serde::Serialize;
+use sqlx::SqliteConnection;
+
+/// Outcome of an access decision, recorded as an access event.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Decision {
+    /// grant or deny
+    pub access: &'static str,
+    /// Why access was denied: code or clock
+    pub reason: Option<&'static str>,
+}
+
+impl Decision {
+    fn grant() -> Self {
+        Self {
+            access: "grant",
+            reason: None,
+        }
+    }
+
+    fn deny(reason: &'static str) -> Self {
+        Self {
+            access: "deny",
+            reason: Some(reason),
+        }
+    }
+}
+
+pub async fn access(
+    code: &str,
+    position: i64,
+    deny_untrusted_clock: bool,
+    database_url: &str,
+) -> anyhow::Result<()> {
+    let mut conn = db::connect(database_url).await?;
+    let decision = decide(code, position, None, deny_untrusted_clock, &mut conn).await?;
+    println!("{}", decision.access.to_uppercase());
+    Ok(())
+}
+
+/// Decide access with code at the point at position and record the event with the reader
+/// that asked, if any. A position without a point is an error rather than a denial.
+pub async fn decide(
+    code: &str,
+    position: i64,
+    reader_id: Option<i64>,
+    deny_untrusted_clock: bool,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Decision> {
+    let started = std::time::Instant::now();
+    let decision = decide_and_record(code, position, reader_id, deny_untrusted_clock, conn).await?;
+    metrics::ACCESS_DECISION_DURATION
+        .with_label_values(&[decision.access])
+        .observe(started.elapsed().as_secs_f64());
+    Ok(decision)
+}
 
-pub async fn access(code: &str, position: i64, database_url: &str) -> anyhow::Result<()> {
+async fn decide_and_record(
+    code: &str,
+    position: i64,
+    reader_id: Option<i64>,
+    deny_untrusted_clock: bool,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Decision> {
     if position < 1 {
         return Err(anyhow::anyhow!(
             "Position is 1-based and must be greater than 0."
         ));
     }
-    let mut conn = SqliteConnection::connect(database_url).await?;
     let active_code = sqlx::query_as!(
         ActiveCode,
         r#"select access_point_id, position, code, access_user_id, activate_code_at, expire_code_at
@@ -16,20 +77,51 @@ pub async fn access(code: &str, position: i64, database_url: &str) -> anyhow::Re
         code,
         position
     )
-    .fetch_optional(&mut conn)
+    .fetch_optional(&mut *conn)
     .await?;
 
+    // Time-bounded codes are only as good as the hub clock heartbeat last checked.
+    let active_code = match active_code {
+        Some(active_code)
+            if deny_untrusted_clock
+                && (active_code.activate_code_at.is_some()
+                    || active_code.expire_code_at.is_some()) =>
+        {
+            let (clock_skew_exceeded,): (bool,) =
+                sqlx::query_as("select exists (select 1 from AccessHub where clock_skew_exceeded)")
+                    .fetch_one(&mut *conn)
+                    .await?;
+            if clock_skew_exceeded {
+                insert_event(
+                    "deny",
+                    &active_code.code,
+                    None,
+                    active_code.access_point_id,
+                    Some("clock"),
+                    reader_id,
+                    conn,
+                )
+                .await?;
+                return Ok(Decision::deny("clock"));
+            }
+            Some(active_code)
+        }
+        active_code => active_code,
+    };
+
     match active_code {
         Some(active_code) => {
-            let _id = sqlx::query!(
-            r#"
-            INSERT INTO AccessEvent (at, access, code, access_user_id, access_point_id) VALUES (CURRENT_TIMESTAMP,'grant', ?, ?, ?)       
-            "#,
-            active_code.code, active_code.access_user_id, active_code.access_point_id)
-                .execute(&mut conn)
-                .await?
-                .last_insert_rowid();
-            println!("GRANT");
+            insert_event(
+                "grant",
+                &active_code.code,
+                Some(active_code.access_user_id),
+                active_code.access_point_id,
+                None,
+                reader_id,
+                conn,
+            )
+            .await?;
+            Ok(Decision::grant())
         }
         None => {
             let point = sqlx::query_as!(
@@ -37,25 +129,96 @@ pub async fn access(code: &str, position: i64, database_url: &str) -> anyhow::Re
                 r#"select id, position from AccessPoint where position = ?"#,
                 position
             )
-            .fetch_optional(&mut conn)
+            .fetch_optional(&mut *conn)
             .await?;
             match point {
                 Some(point) => {
-                    let _id = sqlx::query!(
-                        r#"
-                        INSERT INTO AccessEvent (at, access, code, access_point_id) VALUES (CURRENT_TIMESTAMP,'deny', ?, ?)       
-                        "#,
-                        code, point.id)
-                            .execute(&mut conn)
-                            .await?
-                            .last_insert_rowid();
-                    println!("DENY")
-                }
-                None => {
-                    return Err(anyhow::anyhow!("Position {} does not exist", position));
+                    insert_event("deny", code, None, point.id, Some("code"), reader_id, conn)
+                        .await?;
+                    Ok(Decision::deny("code"))
                 }
+                None => Err(anyhow::anyhow!("Position {} does not exist", position)),
             }
         }
     }
-    Ok(())
+}
+
+/// Insert an access event. The door waits on this write, so it is retried when a heartbeat
+/// holds the write lock past the busy timeout.
+async fn insert_event(
+    access: &str,
+    code: &str,
+    access_user_id: Option<i64>,
+    access_point_id: i64,
+    reason: Option<&str>,
+    access_reader_id: Option<i64>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut attempt = 0;
+    loop {
+        let result = sqlx::query(
+            r#"INSERT INTO AccessEvent (at, access, code, access_user_id, access_point_id, reason, access_reader_id)
+            VALUES (CURRENT_TIMESTAMP, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(access)
+        .bind(code)
+        .bind(access_user_id)
+        .bind(access_point_id)
+        .bind(reason)
+        .bind(access_reader_id)
+        .execute(&mut *conn)
+        .await;
+        match result {
+            Ok(_) => return Ok(()),
+            Err(err) if db::is_busy(&err) && attempt < db::BUSY_RETRIES => {
+                attempt += 1;
+                tracing::warn!(
+                    attempt,
+                    retries = db::BUSY_RETRIES,
+                    "Database busy, retrying"
+                );
+                tokio::time::sleep(db::busy_backoff(attempt)).await;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_decide() {
+    let mut conn = db::test_conn().await;
+    sqlx::query(
+        r#"insert into AccessUser (id, code, expire_code_at) values (10, '555', '2999-01-01 00:00:00');
+        insert into AccessPointToAccessUser (access_point_id, access_user_id) values (1, 10);
+        update AccessHub set clock_skew_exceeded = true;"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    assert_eq!(
+        decide("555", 1, None, false, &mut conn).await.unwrap(),
+        Decision::grant()
+    );
+    assert_eq!(
+        decide("555", 1, None, true, &mut conn).await.unwrap(),
+        Decision::deny("clock")
+    );
+    assert_eq!(
+        decide("000", 1, Some(7), false, &mut conn).await.unwrap(),
+        Decision::deny("code")
+    );
+    assert!(decide("555", 99, None, false, &mut conn).await.is_err());
+    let events: Vec<(String, Option<i64>)> =
+        sqlx::query_as(r#"select access, access_reader_id from AccessEvent order by id"#)
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    assert_eq!(
+        events,
+        vec![
+            ("grant".to_string(), None),
+            ("deny".to_string(), None),
+            ("deny".to_string(), Some(7)),
+        ]
+    );
 }
@@ -0,0 +1,1169 @@
+use crate::domain::{Point, Reader, User};
+use serde_json::json;
+use sqlx::{Connection, SqliteConnection};
+use std::collections::HashMap;
+
+/// Who made a change: --actor, AHUB_ACTOR or the login user.
+pub fn actor(actor: Option<String>) -> String {
+    actor
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Fields of a user to add or update. None leaves an update unchanged.
+#[derive(clap::Args, Debug)]
+pub struct UserFields {
+    /// Code
+    #[clap(short, long)]
+    pub code: Option<String>,
+
+    /// Code is active from, UTC (2022-03-25, 2022-03-25T17:03:01.000Z)
+    #[clap(long, parse(try_from_str = crate::format::parse_timestamp))]
+    pub activate_code_at: Option<chrono::NaiveDateTime>,
+
+    /// Code expires at, UTC (2022-03-25, 2022-03-25T17:03:01.000Z)
+    #[clap(long, parse(try_from_str = crate::format::parse_timestamp))]
+    pub expire_code_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Add a user and return its id.
+pub async fn add_user(
+    id: Option<i64>,
+    fields: &UserFields,
+    positions: &[i64],
+    local: bool,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<i64> {
+    let code = fields
+        .code
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("Missing code"))?;
+    let mut tx = conn.begin().await?;
+    check_code_unused(code, None, &mut tx).await?;
+    let point_ids = point_ids(positions, &mut tx).await?;
+    // Local users count down from -1 so cloud ids, which count up, never reach them.
+    let id = match id {
+        Some(id) => id,
+        None if local => {
+            let (id,): (i64,) =
+                sqlx::query_as(r#"select min(coalesce(min(id), 0), 0) - 1 from AccessUser"#)
+                    .fetch_one(&mut tx)
+                    .await?;
+            id
+        }
+        None => {
+            let (id,): (i64,) =
+                sqlx::query_as(r#"select max(coalesce(max(id), 0), 0) + 1 from AccessUser"#)
+                    .fetch_one(&mut tx)
+                    .await?;
+            id
+        }
+    };
+    check_id_range(id, local)?;
+    if find_user(id, &mut tx).await?.is_some() {
+        return Err(anyhow::anyhow!("User {} already exists", id));
+    }
+    let user = User {
+        id,
+        code: code.to_string(),
+        activate_code_at: fields.activate_code_at,
+        expire_code_at: fields.expire_code_at,
+    };
+    check_code_times(&user)?;
+    sqlx::query(
+        r#"insert into AccessUser (id, code, activate_code_at, expire_code_at, local) values (?, ?, ?, ?, ?)"#,
+    )
+    .bind(user.id)
+    .bind(&user.code)
+    .bind(user.activate_code_at)
+    .bind(user.expire_code_at)
+    .bind(local)
+    .execute(&mut tx)
+    .await?;
+    for point_id in point_ids.iter() {
+        assign_point(id, *point_id, &mut tx).await?;
+    }
+    record_change(
+        actor,
+        "user.add",
+        id,
+        json!({ "user": user, "positions": positions, "local": local }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+pub async fn update_user(
+    id: i64,
+    fields: &UserFields,
+    clear_activate_code_at: bool,
+    clear_expire_code_at: bool,
+    local: Option<bool>,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    let (user, was_local) = find_user(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User {} does not exist", id))?;
+    if let Some(code) = fields.code.as_deref() {
+        check_code_unused(code, Some(id), &mut tx).await?;
+    }
+    let updated = User {
+        id,
+        code: fields.code.clone().unwrap_or(user.code),
+        activate_code_at: if clear_activate_code_at {
+            None
+        } else {
+            fields.activate_code_at.or(user.activate_code_at)
+        },
+        expire_code_at: if clear_expire_code_at {
+            None
+        } else {
+            fields.expire_code_at.or(user.expire_code_at)
+        },
+    };
+    check_code_times(&updated)?;
+    let local = local.unwrap_or(was_local);
+    check_id_range(id, local)?;
+    sqlx::query(
+        r#"update AccessUser set code = ?, activate_code_at = ?, expire_code_at = ?, local = ? where id = ?"#,
+    )
+    .bind(&updated.code)
+    .bind(updated.activate_code_at)
+    .bind(updated.expire_code_at)
+    .bind(local)
+    .bind(id)
+    .execute(&mut tx)
+    .await?;
+    record_change(
+        actor,
+        "user.update",
+        id,
+        json!({ "user": updated, "local": local }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    warn_cloud_user(id, local);
+    Ok(())
+}
+
+pub async fn remove_user(id: i64, actor: &str, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    let (user, local) = find_user(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User {} does not exist", id))?;
+    sqlx::query(r#"delete from AccessUser where id = ?"#)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    record_change(
+        actor,
+        "user.remove",
+        id,
+        json!({ "code": user.code, "local": local }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    warn_cloud_user(id, local);
+    Ok(())
+}
+
+pub async fn assign(
+    id: i64,
+    position: i64,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    let (_, local) = find_user(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User {} does not exist", id))?;
+    let point_id = point_ids(&[position], &mut tx).await?[0];
+    let (assigned,): (bool,) = sqlx::query_as(
+        r#"select count(*) > 0 from AccessPointToAccessUser where access_point_id = ? and access_user_id = ?"#,
+    )
+    .bind(point_id)
+    .bind(id)
+    .fetch_one(&mut tx)
+    .await?;
+    if assigned {
+        return Err(anyhow::anyhow!(
+            "User {} is already assigned to position {}",
+            id,
+            position
+        ));
+    }
+    assign_point(id, point_id, &mut tx).await?;
+    record_change(
+        actor,
+        "user.assign",
+        id,
+        json!({ "position": position }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    warn_cloud_user(id, local);
+    Ok(())
+}
+
+pub async fn unassign(
+    id: i64,
+    position: i64,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    let (_, local) = find_user(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User {} does not exist", id))?;
+    let point_id = point_ids(&[position], &mut tx).await?[0];
+    let rows_affected = sqlx::query(
+        r#"delete from AccessPointToAccessUser where access_point_id = ? and access_user_id = ?"#,
+    )
+    .bind(point_id)
+    .bind(id)
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    if rows_affected != 1 {
+        return Err(anyhow::anyhow!(
+            "User {} is not assigned to position {}",
+            id,
+            position
+        ));
+    }
+    record_change(
+        actor,
+        "user.unassign",
+        id,
+        json!({ "position": position }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    warn_cloud_user(id, local);
+    Ok(())
+}
+
+/// Add a point at position. Returns its id, the next free one when not given.
+pub async fn add_point(
+    id: Option<i64>,
+    position: i64,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<i64> {
+    if position < 1 {
+        return Err(anyhow::anyhow!(
+            "Position is 1-based and must be greater than 0."
+        ));
+    }
+    let mut tx = conn.begin().await?;
+    if find_point(position, &mut tx).await?.is_some() {
+        return Err(anyhow::anyhow!("Position {} already exists", position));
+    }
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let (id,): (i64,) =
+                sqlx::query_as(r#"select coalesce(max(id), 0) + 1 from AccessPoint"#)
+                    .fetch_one(&mut tx)
+                    .await?;
+            id
+        }
+    };
+    let (exists,): (bool,) = sqlx::query_as(r#"select count(*) > 0 from AccessPoint where id = ?"#)
+        .bind(id)
+        .fetch_one(&mut tx)
+        .await?;
+    if exists {
+        return Err(anyhow::anyhow!("Point {} already exists", id));
+    }
+    sqlx::query(r#"insert into AccessPoint (id, position) values (?, ?)"#)
+        .bind(id)
+        .bind(position)
+        .execute(&mut tx)
+        .await?;
+    record_change(
+        actor,
+        "point.add",
+        id,
+        json!({ "position": position }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Remove a point and its assignments. A point with access events is kept for their history.
+/// Returns the point id and the number of assignments removed.
+pub async fn remove_point(
+    position: i64,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<(i64, u64)> {
+    let mut tx = conn.begin().await?;
+    let point = find_point(position, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Position {} does not exist", position))?;
+    let (events,): (i64,) =
+        sqlx::query_as(r#"select count(*) from AccessEvent where access_point_id = ?"#)
+            .bind(point.id)
+            .fetch_one(&mut tx)
+            .await?;
+    if events > 0 {
+        return Err(anyhow::anyhow!(
+            "Point {} at position {} has {} access events",
+            point.id,
+            position,
+            events
+        ));
+    }
+    let assignments =
+        sqlx::query(r#"delete from AccessPointToAccessUser where access_point_id = ?"#)
+            .bind(point.id)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+    sqlx::query(r#"delete from AccessPoint where id = ?"#)
+        .bind(point.id)
+        .execute(&mut tx)
+        .await?;
+    record_change(
+        actor,
+        "point.remove",
+        point.id,
+        json!({ "position": position, "assignments": assignments }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok((point.id, assignments))
+}
+
+/// Readers authenticate with their secret alone, so it must be long enough not to guess.
+const MIN_SECRET_LEN: usize = 16;
+
+/// Add a reader that may ask for decisions at the points at positions. Returns its id and
+/// secret, which is generated when not given.
+pub async fn add_reader(
+    id: Option<i64>,
+    name: &str,
+    secret: Option<&str>,
+    positions: &[i64],
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<(i64, String)> {
+    let secret = match secret {
+        Some(secret) => secret.to_string(),
+        None => generate_secret()?,
+    };
+    let mut tx = conn.begin().await?;
+    check_reader_unused(name, Some(&secret), None, &mut tx).await?;
+    let point_ids = point_ids(positions, &mut tx).await?;
+    if let Some(id) = id {
+        if find_reader(id, &mut tx).await?.is_some() {
+            return Err(anyhow::anyhow!("Reader {} already exists", id));
+        }
+    }
+    // Without an id autoincrement picks one no earlier reader had.
+    let id = sqlx::query(r#"insert into AccessReader (id, name, secret_hash) values (?, ?, ?)"#)
+        .bind(id)
+        .bind(name)
+        .bind(crate::reader::secret_hash(&secret))
+        .execute(&mut tx)
+        .await?
+        .last_insert_rowid();
+    for point_id in point_ids {
+        assign_reader_point(id, point_id, &mut tx).await?;
+    }
+    record_change(
+        actor,
+        "reader.add",
+        id,
+        json!({ "name": name, "positions": positions }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok((id, secret))
+}
+
+/// Rename a reader or replace its secret. Returns the new secret when one was generated.
+pub async fn update_reader(
+    id: i64,
+    name: Option<&str>,
+    rotate_secret: bool,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Option<String>> {
+    let secret = match rotate_secret {
+        true => Some(generate_secret()?),
+        false => None,
+    };
+    let mut tx = conn.begin().await?;
+    let reader = find_reader(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Reader {} does not exist", id))?;
+    let name = name.unwrap_or(&reader.name);
+    check_reader_unused(name, secret.as_deref(), Some(id), &mut tx).await?;
+    sqlx::query(
+        r#"update AccessReader set name = ?1, secret_hash = coalesce(?2, secret_hash),
+        secret = case when ?2 is null then secret end where id = ?3"#,
+    )
+    .bind(name)
+    .bind(secret.as_deref().map(crate::reader::secret_hash))
+    .bind(id)
+    .execute(&mut tx)
+    .await?;
+    record_change(
+        actor,
+        "reader.update",
+        id,
+        json!({ "name": name, "rotateSecret": rotate_secret }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(secret)
+}
+
+/// Remove a reader and its assignments. Its events keep the reader id.
+pub async fn remove_reader(
+    id: i64,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    let reader = find_reader(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Reader {} does not exist", id))?;
+    sqlx::query(r#"delete from AccessReader where id = ?"#)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    record_change(
+        actor,
+        "reader.remove",
+        id,
+        json!({ "name": reader.name }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn assign_reader(
+    id: i64,
+    position: i64,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    find_reader(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Reader {} does not exist", id))?;
+    let point_id = point_ids(&[position], &mut tx).await?[0];
+    let (assigned,): (bool,) = sqlx::query_as(
+        r#"select count(*) > 0 from AccessPointToAccessReader where access_point_id = ? and access_reader_id = ?"#,
+    )
+    .bind(point_id)
+    .bind(id)
+    .fetch_one(&mut tx)
+    .await?;
+    if assigned {
+        return Err(anyhow::anyhow!(
+            "Reader {} is already assigned to position {}",
+            id,
+            position
+        ));
+    }
+    assign_reader_point(id, point_id, &mut tx).await?;
+    record_change(
+        actor,
+        "reader.assign",
+        id,
+        json!({ "position": position }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn unassign_reader(
+    id: i64,
+    position: i64,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut tx = conn.begin().await?;
+    find_reader(id, &mut tx)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Reader {} does not exist", id))?;
+    let point_id = point_ids(&[position], &mut tx).await?[0];
+    let rows_affected = sqlx::query(
+        r#"delete from AccessPointToAccessReader where access_point_id = ? and access_reader_id = ?"#,
+    )
+    .bind(point_id)
+    .bind(id)
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    if rows_affected != 1 {
+        return Err(anyhow::anyhow!(
+            "Reader {} is not assigned to position {}",
+            id,
+            position
+        ));
+    }
+    record_change(
+        actor,
+        "reader.unassign",
+        id,
+        json!({ "position": position }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// User columns and its local flag.
+type UserRow = (
+    i64,
+    String,
+    Option<chrono::NaiveDateTime>,
+    Option<chrono::NaiveDateTime>,
+    bool,
+);
+
+async fn find_user(id: i64, conn: &mut SqliteConnection) -> anyhow::Result<Option<(User, bool)>> {
+    let row: Option<UserRow> = sqlx::query_as(
+        r#"select id, code, activate_code_at, expire_code_at, local from AccessUser where id = ?"#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(
+        row.map(|(id, code, activate_code_at, expire_code_at, local)| {
+            (
+                User {
+                    id,
+                    code,
+                    activate_code_at,
+                    expire_code_at,
+                },
+                local,
+            )
+        }),
+    )
+}
+
+async fn find_point(position: i64, conn: &mut SqliteConnection) -> anyhow::Result<Option<Point>> {
+    let point =
+        sqlx::query_as::<_, Point>(r#"select id, position from AccessPoint where position = ?"#)
+            .bind(position)
+            .fetch_optional(&mut *conn)
+            .await?;
+    Ok(point)
+}
+
+async fn point_ids(positions: &[i64], conn: &mut SqliteConnection) -> anyhow::Result<Vec<i64>> {
+    let mut ids = Vec::<i64>::new();
+    for position in positions {
+        let point = find_point(*position, &mut *conn)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Position {} does not exist", position))?;
+        if ids.contains(&point.id) {
+            return Err(anyhow::anyhow!("Duplicate position {}", position));
+        }
+        ids.push(point.id);
+    }
+    Ok(ids)
+}
+
+async fn find_reader(id: i64, conn: &mut SqliteConnection) -> anyhow::Result<Option<Reader>> {
+    let reader = sqlx::query_as::<_, Reader>(
+        r#"select id, name, last_seen_at from AccessReader where id = ?"#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(reader)
+}
+
+/// Names and secrets are unique so each tells readers apart. Secret is None when it stays.
+async fn check_reader_unused(
+    name: &str,
+    secret: Option<&str>,
+    except_id: Option<i64>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    if name.is_empty() {
+        return Err(anyhow::anyhow!("Missing reader name"));
+    }
+    if matches!(secret, Some(s) if s.len() < MIN_SECRET_LEN) {
+        return Err(anyhow::anyhow!(
+            "Reader secret must be at least {} characters",
+            MIN_SECRET_LEN
+        ));
+    }
+    let other: Option<(i64, String)> = sqlx::query_as(
+        r#"select id, name from AccessReader where (name = ? or secret_hash = ?) and id is not ?"#,
+    )
+    .bind(name)
+    .bind(secret.map(crate::reader::secret_hash))
+    .bind(except_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    match other {
+        Some((other, other_name)) if other_name == name => Err(anyhow::anyhow!(
+            "Name {} already belongs to reader {}",
+            name,
+            other
+        )),
+        Some((other, _)) => Err(anyhow::anyhow!(
+            "Secret already belongs to reader {}",
+            other
+        )),
+        None => Ok(()),
+    }
+}
+
+/// 24 random bytes from the system, hex encoded.
+fn generate_secret() -> anyhow::Result<String> {
+    use std::io::Read;
+    let mut bytes = [0u8; 24];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Local users have negative ids and cloud users positive ones, so the cloud can neither
+/// update a local user nor be kept from deleting one of its own.
+fn check_id_range(id: i64, local: bool) -> anyhow::Result<()> {
+    match (local, id < 0) {
+        (true, false) => Err(anyhow::anyhow!(
+            "User {} can not be local, local user ids are negative",
+            id
+        )),
+        (false, true) => Err(anyhow::anyhow!(
+            "User {} must be local, negative ids are for local users",
+            id
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_code_times(user: &User) -> anyhow::Result<()> {
+    if let (Some(activate_code_at), Some(expire_code_at)) =
+        (user.activate_code_at, user.expire_code_at)
+    {
+        if expire_code_at <= activate_code_at {
+            return Err(anyhow::anyhow!(
+                "User {} code expires at or before it activates",
+                user.id
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Codes are unique so a code identifies one user at a point.
+async fn check_code_unused(
+    code: &str,
+    except_id: Option<i64>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    if code.is_empty() {
+        return Err(anyhow::anyhow!("Code must not be empty"));
+    }
+    let other: Option<(i64,)> =
+        sqlx::query_as(r#"select id from AccessUser where code = ? and id is not ?"#)
+            .bind(code)
+            .bind(except_id)
+            .fetch_optional(&mut *conn)
+            .await?;
+    if let Some((other,)) = other {
+        return Err(anyhow::anyhow!(
+            "Code {} already belongs to user {}",
+            code,
+            other
+        ));
+    }
+    Ok(())
+}
+
+async fn assign_point(
+    user_id: i64,
+    point_id: i64,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"insert into AccessPointToAccessUser (access_point_id, access_user_id) values (?, ?)"#,
+    )
+    .bind(point_id)
+    .bind(user_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn assign_reader_point(
+    reader_id: i64,
+    point_id: i64,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"insert into AccessPointToAccessReader (access_point_id, access_reader_id) values (?, ?)"#,
+    )
+    .bind(point_id)
+    .bind(reader_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn record_change(
+    actor: &str,
+    action: &str,
+    record_id: impl Into<Option<i64>>,
+    detail: serde_json::Value,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"insert into AdminChange (actor, action, record_id, detail) values (?, ?, ?, ?)"#,
+    )
+    .bind(actor)
+    .bind(action)
+    .bind(record_id.into())
+    .bind(detail.to_string())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// User row of an import file.
+#[derive(Debug)]
+struct ImportUser {
+    line: u64,
+    user: User,
+    /// None without a positions column, which leaves the assignments of updated users alone.
+    positions: Option<Vec<i64>>,
+}
+
+/// Create or update the users in a CSV file with columns id, code, activateCodeAt,
+/// expireCodeAt and positions, the same as `dump users --format csv`. Without a positions
+/// column updated users keep their points. Everything is validated before one transaction
+/// applies it.
+pub async fn import_users(
+    file: &str,
+    local: bool,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let mut reader = csv::Reader::from_path(file)?;
+    let (created, updated) = import(&mut reader, local, actor, conn).await?;
+    println!(
+        "Imported {} users from {}: {} created, {} updated",
+        created + updated,
+        file,
+        created,
+        updated
+    );
+    Ok(())
+}
+
+async fn import<R: std::io::Read>(
+    reader: &mut csv::Reader<R>,
+    local: bool,
+    actor: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<(usize, usize)> {
+    let mut tx = conn.begin().await?;
+    let positions: HashMap<i64, i64> =
+        sqlx::query_as::<_, Point>(r#"select id, position from AccessPoint"#)
+            .fetch_all(&mut tx)
+            .await?
+            .into_iter()
+            .map(|p| (p.position, p.id))
+            .collect();
+    let existing: HashMap<i64, (User, bool)> = sqlx::query_as::<_, UserRow>(
+        r#"select id, code, activate_code_at, expire_code_at, local from AccessUser"#,
+    )
+    .fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .map(|(id, code, activate_code_at, expire_code_at, local)| {
+        let user = User {
+            id,
+            code,
+            activate_code_at,
+            expire_code_at,
+        };
+        (id, (user, local))
+    })
+    .collect();
+
+    let (users, problems) = read_users(reader, &positions, &existing, local)?;
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Invalid import with {} problem(s):\n{}",
+            problems.len(),
+            problems
+                .iter()
+                .map(|p| format!("  {}", p))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    // Codes must stay unique: update recycled codes, update, create, like heartbeat sync.
+    let (update_users, create_users): (Vec<&ImportUser>, Vec<&ImportUser>) = users
+        .iter()
+        .partition(|u| existing.contains_key(&u.user.id));
+    for u in update_users.iter() {
+        let (old, _) = &existing[&u.user.id];
+        if old.code != u.user.code {
+            sqlx::query(r#"update AccessUser set code = ? where id = ?"#)
+                .bind(format!("{}-", old.code))
+                .bind(old.id)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+    // Without --local an update keeps whether the user is local.
+    for u in update_users.iter() {
+        sqlx::query(
+            r#"update AccessUser set code = ?, activate_code_at = ?, expire_code_at = ?, local = local or ? where id = ?"#,
+        )
+        .bind(&u.user.code)
+        .bind(u.user.activate_code_at)
+        .bind(u.user.expire_code_at)
+        .bind(local)
+        .bind(u.user.id)
+        .execute(&mut tx)
+        .await?;
+        if u.positions.is_some() {
+            sqlx::query(r#"delete from AccessPointToAccessUser where access_user_id = ?"#)
+                .bind(u.user.id)
+                .execute(&mut tx)
+                .await?;
+        }
+    }
+    for u in create_users.iter() {
+        sqlx::query(
+            r#"insert into AccessUser (id, code, activate_code_at, expire_code_at, local) values (?, ?, ?, ?, ?)"#,
+        )
+        .bind(u.user.id)
+        .bind(&u.user.code)
+        .bind(u.user.activate_code_at)
+        .bind(u.user.expire_code_at)
+        .bind(local)
+        .execute(&mut tx)
+        .await?;
+    }
+    for u in users.iter() {
+        for position in u.positions.iter().flatten() {
+            assign_point(u.user.id, positions[position], &mut tx).await?;
+        }
+    }
+    record_change(
+        actor,
+        "user.import",
+        None,
+        json!({ "created": create_users.len(), "updated": update_users.len(), "local": local }),
+        &mut tx,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok((create_users.len(), update_users.len()))
+}
+
+/// Parse every row and collect all problems so a file is fixed in one pass.
+fn read_users<R: std::io::Read>(
+    reader: &mut csv::Reader<R>,
+    positions: &HashMap<i64, i64>,
+    existing: &HashMap<i64, (User, bool)>,
+    local: bool,
+) -> anyhow::Result<(Vec<ImportUser>, Vec<String>)> {
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let (id_column, code_column) = match (column("id"), column("code")) {
+        (Some(id), Some(code)) => (id, code),
+        _ => return Err(anyhow::anyhow!("Import needs id and code columns")),
+    };
+    let activate_column = column("activateCodeAt");
+    let expire_column = column("expireCodeAt");
+    let positions_column = column("positions");
+
+    let mut users = Vec::<ImportUser>::new();
+    let mut problems = Vec::<String>::new();
+    let mut ids = HashMap::<i64, u64>::new();
+    let mut codes = HashMap::<String, u64>::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|c| record.get(c))
+                .map(|v| v.trim())
+                .unwrap_or_default()
+        };
+        let mut problem = |message: String| problems.push(format!("line {}: {}", line, message));
+
+        let id = match field(Some(id_column)).parse::<i64>() {
+            Ok(id) => id,
+            Err(_) => {
+                problem(format!("invalid id {}", field(Some(id_column))));
+                continue;
+            }
+        };
+        if let Some(first) = ids.insert(id, line) {
+            problem(format!("duplicate id {}, first at line {}", id, first));
+        }
+        let user_local = local || matches!(existing.get(&id), Some((_, true)));
+        match (user_local, id < 0) {
+            (true, false) => problem(format!("id {} is not negative like local user ids", id)),
+            (false, true) => problem(format!("id {} is negative but the user is not local", id)),
+            _ => {}
+        }
+        let code = field(Some(code_column)).to_string();
+        if code.is_empty() {
+            problem("missing code".into());
+        } else if let Some(first) = codes.insert(code.clone(), line) {
+            problem(format!("duplicate code {}, first at line {}", code, first));
+        }
+        let mut timestamp = |name: &str, column: Option<usize>| match field(column) {
+            "" => None,
+            v => match crate::format::parse_timestamp(v) {
+                Ok(dt) => Some(dt),
+                Err(e) => {
+                    problem(format!("{}: {}", name, e));
+                    None
+                }
+            },
+        };
+        let activate_code_at = timestamp("activateCodeAt", activate_column);
+        let expire_code_at = timestamp("expireCodeAt", expire_column);
+        if let (Some(activate_code_at), Some(expire_code_at)) = (activate_code_at, expire_code_at) {
+            if expire_code_at <= activate_code_at {
+                problem("expireCodeAt is at or before activateCodeAt".into());
+            }
+        }
+        let mut user_positions = Vec::<i64>::new();
+        for p in field(positions_column)
+            .split(|c: char| c.is_whitespace() || c == ';')
+            .filter(|p| !p.is_empty())
+        {
+            match p.parse::<i64>() {
+                Ok(position) if !positions.contains_key(&position) => {
+                    problem(format!("unknown position {}", position))
+                }
+                Ok(position) if user_positions.contains(&position) => {
+                    problem(format!("duplicate position {}", position))
+                }
+                Ok(position) => user_positions.push(position),
+                Err(_) => problem(format!("invalid position {}", p)),
+            }
+        }
+        users.push(ImportUser {
+            line,
+            user: User {
+                id,
+                code,
+                activate_code_at,
+                expire_code_at,
+            },
+            positions: positions_column.map(|_| user_positions),
+        });
+    }
+
+    // A code may move between users in the file but not take one from a user outside it.
+    for u in users.iter() {
+        if let Some((owner, _)) = existing
+            .values()
+            .find(|(e, _)| e.code == u.user.code && e.id != u.user.id && !ids.contains_key(&e.id))
+        {
+            problems.push(format!(
+                "line {}: code {} belongs to user {}",
+                u.line, u.user.code, owner.id
+            ));
+        }
+    }
+    Ok((users, problems))
+}
+
+fn warn_cloud_user(id: i64, local: bool) {
+    if !local {
+        tracing::warn!(
+            "User {} is managed by the cloud, the next heartbeat replaces this change",
+            id
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_user_admin() {
+    let mut conn = crate::db::test_conn().await;
+    let fields = |code: &str| UserFields {
+        code: Some(code.into()),
+        activate_code_at: None,
+        expire_code_at: None,
+    };
+    let id = add_user(None, &fields("111"), &[1, 2], true, "test", &mut conn)
+        .await
+        .unwrap();
+    assert_eq!(id, -1);
+    assert!(add_user(None, &fields("111"), &[], true, "test", &mut conn)
+        .await
+        .is_err());
+    assert!(add_user(None, &fields(""), &[], true, "test", &mut conn)
+        .await
+        .is_err());
+    assert!(
+        add_user(Some(6), &fields("666"), &[], true, "test", &mut conn)
+            .await
+            .is_err()
+    );
+    assert!(
+        add_user(None, &fields("222"), &[9], true, "test", &mut conn)
+            .await
+            .is_err()
+    );
+    add_user(Some(5), &fields("222"), &[], false, "test", &mut conn)
+        .await
+        .unwrap();
+    assert!(
+        update_user(5, &fields("111"), false, false, None, "test", &mut conn)
+            .await
+            .is_err()
+    );
+    let times = UserFields {
+        code: None,
+        activate_code_at: Some(chrono::NaiveDate::from_ymd(2022, 3, 25).and_hms(0, 0, 0)),
+        expire_code_at: Some(chrono::NaiveDate::from_ymd(2022, 3, 24).and_hms(0, 0, 0)),
+    };
+    assert!(
+        update_user(5, &times, false, false, None, "test", &mut conn)
+            .await
+            .is_err()
+    );
+    assert!(update_user(
+        5,
+        &fields("555"),
+        false,
+        false,
+        Some(true),
+        "test",
+        &mut conn
+    )
+    .await
+    .is_err());
+    unassign(-1, 2, "test", &mut conn).await.unwrap();
+    assert!(unassign(-1, 2, "test", &mut conn).await.is_err());
+    remove_user(5, "test", &mut conn).await.unwrap();
+
+    let changes: Vec<(String, i64)> =
+        sqlx::query_as(r#"select action, record_id from AdminChange order by id"#)
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    assert_eq!(
+        changes,
+        vec![
+            ("user.add".to_string(), -1),
+            ("user.add".to_string(), 5),
+            ("user.unassign".to_string(), -1),
+            ("user.remove".to_string(), 5),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_import() {
+    let mut conn = crate::db::test_conn().await;
+    let mut reader = csv::Reader::from_reader(
+        "id,code,activateCodeAt,expireCodeAt,positions\n1,111,,,1 2\n2,222,2022-01-01,,3\n"
+            .as_bytes(),
+    );
+    assert_eq!(
+        import(&mut reader, false, "test", &mut conn).await.unwrap(),
+        (2, 0)
+    );
+
+    // Swapped codes go through the recycled suffix.
+    let mut reader = csv::Reader::from_reader("id,code,positions\n1,222,1\n2,111,\n".as_bytes());
+    assert_eq!(
+        import(&mut reader, false, "test", &mut conn).await.unwrap(),
+        (0, 2)
+    );
+    let codes: Vec<(i64, String)> =
+        sqlx::query_as(r#"select id, code from AccessUser order by id"#)
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    assert_eq!(codes, vec![(1, "222".into()), (2, "111".into())]);
+
+    let mut reader = csv::Reader::from_reader(
+        "id,code,activateCodeAt,expireCodeAt,positions\n3,333,,tomorrow,9\n3,333,,,1\nx,444,,,\n4,111,,,\n5,555,2022-01-02,2022-01-01,\n-6,666,,,\n"
+            .as_bytes(),
+    );
+    let err = import(&mut reader, false, "test", &mut conn)
+        .await
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err,
+        "Invalid import with 8 problem(s):
+  line 2: expireCodeAt: Invalid timestamp tomorrow
+  line 2: unknown position 9
+  line 3: duplicate id 3, first at line 2
+  line 3: duplicate code 333, first at line 2
+  line 4: invalid id x
+  line 6: expireCodeAt is at or before activateCodeAt
+  line 7: id -6 is negative but the user is not local
+  line 5: code 111 belongs to user 2"
+    );
+
+    // Local users stay local when imported again without --local.
+    let mut reader = csv::Reader::from_reader("id,code,positions\n-1,777,1\n".as_bytes());
+    assert_eq!(
+        import(&mut reader, true, "test", &mut conn).await.unwrap(),
+        (1, 0)
+    );
+    let mut reader = csv::Reader::from_reader("id,code,positions\n-1,778,1\n".as_bytes());
+    assert_eq!(
+        import(&mut reader, false, "test", &mut conn).await.unwrap(),
+        (0, 1)
+    );
+    let (local,): (bool,) = sqlx::query_as(r#"select local from AccessUser where id = -1"#)
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+    assert!(local);
+    let mut reader = csv::Reader::from_reader("id,code,positions\n1,222,1\n".as_bytes());
+    assert!(import(&mut reader, true, "test", &mut conn).await.is_err());
+    // Without a positions column users keep their points.
+    let mut reader = csv::Reader::from_reader("id,code\n1,223\n-1,779\n".as_bytes());
+    assert_eq!(
+        import(&mut reader, false, "test", &mut conn).await.unwrap(),
+        (0, 2)
+    );
+    let assignments: Vec<(i64, i64)> = sqlx::query_as(
+        r#"select access_user_id, access_point_id from AccessPointToAccessUser
+        order by access_user_id, access_point_id"#,
+    )
+    .fetch_all(&mut conn)
+    .await
+    .unwrap();
+    assert_eq!(assignments, vec![(-1, 1), (1, 1)]);
+}
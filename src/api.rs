@@ -0,0 +1,469 @@
+use crate::admin::{self, UserFields};
+use crate::db;
+use crate::dump;
+use crate::events::{self, EventFilter};
+use crate::format::parse_timestamp;
+use crate::json::json_option_naive_date_time;
+use crate::serve::State;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+/// Changes made through the API are recorded with this actor.
+const ACTOR: &str = "api";
+
+/// Error answered as `{"error": message}`.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl ToString) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        json_response(self.status, &json!({ "error": self.message }))
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+    }
+}
+
+/// Admin operations validate their input, so their errors are the client's.
+fn rejected(err: anyhow::Error) -> ApiError {
+    ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", err))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Page {
+    #[serde(default = "default_take")]
+    take: i32,
+    #[serde(default)]
+    skip: i32,
+}
+
+fn default_take() -> i32 {
+    50
+}
+
+/// Most records a page may take. SQLite reads a negative limit as no limit at all.
+const MAX_TAKE: i32 = 1000;
+
+fn check_take(take: i32) -> Result<(), ApiError> {
+    if !(1..=MAX_TAKE).contains(&take) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("take must be between 1 and {}", MAX_TAKE),
+        ));
+    }
+    Ok(())
+}
+
+impl Page {
+    fn check(&self) -> Result<(), ApiError> {
+        check_take(self.take)?;
+        if self.skip < 0 {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "skip must not be negative",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Event filter and cursor. Timestamps take the same forms as the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventsQuery {
+    take: Option<i32>,
+    before: Option<i64>,
+    since: Option<String>,
+    until: Option<String>,
+    position: Option<i64>,
+    user: Option<i64>,
+    code: Option<String>,
+    access: Option<String>,
+    reason: Option<String>,
+    reader: Option<i64>,
+}
+
+impl EventsQuery {
+    fn filter(&self) -> Result<EventFilter, ApiError> {
+        let timestamp = |s: &Option<String>| {
+            s.as_deref()
+                .map(parse_timestamp)
+                .transpose()
+                .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))
+        };
+        Ok(EventFilter {
+            since: timestamp(&self.since)?,
+            until: timestamp(&self.until)?,
+            position: self.position,
+            user: self.user,
+            code: self.code.clone(),
+            access: self.access.clone(),
+            reason: self.reason.clone(),
+            reader: self.reader,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct UserBody {
+    id: Option<i64>,
+    code: Option<String>,
+    #[serde(default, with = "json_option_naive_date_time")]
+    activate_code_at: Option<chrono::NaiveDateTime>,
+    #[serde(default, with = "json_option_naive_date_time")]
+    expire_code_at: Option<chrono::NaiveDateTime>,
+    #[serde(default)]
+    clear_activate_code_at: bool,
+    #[serde(default)]
+    clear_expire_code_at: bool,
+    /// Point positions (1-based), only when adding
+    #[serde(default)]
+    positions: Vec<i64>,
+    local: Option<bool>,
+}
+
+impl UserBody {
+    fn fields(&self) -> UserFields {
+        UserFields {
+            code: self.code.clone(),
+            activate_code_at: self.activate_code_at,
+            expire_code_at: self.expire_code_at,
+        }
+    }
+}
+
+/// Answer a request under /api. Every request needs `Authorization: Bearer <admin token>`.
+pub async fn route(state: &State, req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    authorize(state, &req)?;
+    let method = req.method().clone();
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let segments: Vec<&str> = path.split('/').skip(2).collect();
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["hub"]) => {
+            let hub = dump::select_hub(&mut *state.conn.lock().await).await?;
+            Ok(json_response(StatusCode::OK, &hub))
+        }
+        (&Method::GET, ["points"]) => {
+            let page: Page = query(&req)?;
+            page.check()?;
+            let points =
+                dump::select_points(page.take, page.skip, &mut *state.conn.lock().await).await?;
+            Ok(json_response(StatusCode::OK, &points))
+        }
+        (&Method::GET, ["users"]) => {
+            let page: Page = query(&req)?;
+            page.check()?;
+            let users =
+                dump::select_users(page.take, page.skip, &mut *state.conn.lock().await).await?;
+            Ok(json_response(StatusCode::OK, &users))
+        }
+        (&Method::GET, ["readers"]) => {
+            let readers = dump::select_readers(&mut *state.conn.lock().await).await?;
+            Ok(json_response(StatusCode::OK, &readers))
+        }
+        (&Method::GET, ["codes"]) => {
+            let codes = dump::select_codes(&mut *state.conn.lock().await).await?;
+            Ok(json_response(StatusCode::OK, &codes))
+        }
+        (&Method::GET, ["events"]) => {
+            let q: EventsQuery = query(&req)?;
+            let take = q.take.unwrap_or_else(default_take);
+            check_take(take)?;
+            let filter = q.filter()?;
+            let events =
+                events::select_events(&filter, q.before, take, &mut *state.conn.lock().await)
+                    .await?;
+            // The id of the last event is the cursor of the next page.
+            let next_before = match events.last() {
+                Some(last) if events.len() == take as usize => Some(last.id),
+                _ => None,
+            };
+            Ok(json_response(
+                StatusCode::OK,
+                &json!({ "events": events, "nextBefore": next_before }),
+            ))
+        }
+        (&Method::POST, ["users"]) => {
+            let body: UserBody = body(req).await?;
+            let id = admin::add_user(
+                body.id,
+                &body.fields(),
+                &body.positions,
+                body.local.unwrap_or(false),
+                ACTOR,
+                &mut *state.conn.lock().await,
+            )
+            .await
+            .map_err(rejected)?;
+            Ok(json_response(StatusCode::CREATED, &json!({ "id": id })))
+        }
+        (&Method::PATCH, ["users", id]) => {
+            let id = path_id(id)?;
+            let body: UserBody = body(req).await?;
+            if body.id.is_some() || !body.positions.is_empty() {
+                return Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "id and positions can not be updated, assign and unassign positions instead",
+                ));
+            }
+            admin::update_user(
+                id,
+                &body.fields(),
+                body.clear_activate_code_at,
+                body.clear_expire_code_at,
+                body.local,
+                ACTOR,
+                &mut *state.conn.lock().await,
+            )
+            .await
+            .map_err(rejected)?;
+            Ok(json_response(StatusCode::OK, &json!({ "id": id })))
+        }
+        (&Method::DELETE, ["users", id]) => {
+            let id = path_id(id)?;
+            admin::remove_user(id, ACTOR, &mut *state.conn.lock().await)
+                .await
+                .map_err(rejected)?;
+            Ok(json_response(StatusCode::OK, &json!({ "id": id })))
+        }
+        (&Method::PUT, ["users", id, "points", position]) => {
+            let (id, position) = (path_id(id)?, path_id(position)?);
+            admin::assign(id, position, ACTOR, &mut *state.conn.lock().await)
+                .await
+                .map_err(rejected)?;
+            Ok(json_response(StatusCode::OK, &json!({ "id": id })))
+        }
+        (&Method::DELETE, ["users", id, "points", position]) => {
+            let (id, position) = (path_id(id)?, path_id(position)?);
+            admin::unassign(id, position, ACTOR, &mut *state.conn.lock().await)
+                .await
+                .map_err(rejected)?;
+            Ok(json_response(StatusCode::OK, &json!({ "id": id })))
+        }
+        (&Method::POST, ["heartbeat"]) => {
+            let _running = state.heartbeat.try_lock().map_err(|_| {
+                ApiError::new(StatusCode::CONFLICT, "A heartbeat is already running")
+            })?;
+            // Its own connection, so requests are not held up for the cloud round trip.
+            let mut conn = db::connect(&state.database_url).await?;
+            let counts = state
+                .sync(&mut conn)
+                .await
+                .map_err(|e| ApiError::new(StatusCode::BAD_GATEWAY, format!("{:#}", e)))?;
+            Ok(json_response(StatusCode::OK, &counts))
+        }
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+fn authorize(state: &State, req: &Request<Body>) -> Result<(), ApiError> {
+    let settings = state.settings();
+    let admin_token = settings.admin_token.as_deref().ok_or_else(|| {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "API is disabled: set --admin-token or AHUB_ADMIN_TOKEN",
+        )
+    })?;
+    match bearer(req) {
+        Some(token) if secret_eq(token, admin_token) => Ok(()),
+        _ => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Missing or wrong admin token",
+        )),
+    }
+}
+
+/// Token of an `Authorization: Bearer <token>` header.
+pub fn bearer(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Compare secrets in time independent of where they differ.
+pub fn secret_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn query<T: DeserializeOwned>(req: &Request<Body>) -> Result<T, ApiError> {
+    serde_urlencoded::from_str(req.uri().query().unwrap_or(""))
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid query: {}", e)))
+}
+
+/// Largest request body read, so a client can not make the hub buffer without end.
+const MAX_BODY: usize = 64 * 1024;
+
+pub async fn body<T: DeserializeOwned>(req: Request<Body>) -> Result<T, ApiError> {
+    use hyper::body::HttpBody;
+    let too_large = || {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Body is larger than {} bytes", MAX_BODY),
+        )
+    };
+    let mut body = req.into_body();
+    if body.size_hint().lower() > MAX_BODY as u64 {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+        if bytes.len() + chunk.len() > MAX_BODY {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(&bytes)).map_err(
+        |e| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid body at {}: {}", e.path(), e.inner()),
+            )
+        },
+    )
+}
+
+fn path_id(s: &str) -> Result<i64, ApiError> {
+    s.parse()
+        .map_err(|_| ApiError::new(StatusCode::NOT_FOUND, "Not found"))
+}
+
+pub fn json_response<T: Serialize + ?Sized>(status: StatusCode, value: &T) -> Response<Body> {
+    let mut response = Response::new(Body::from(serde_json::to_string(value).unwrap_or_default()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+#[tokio::test]
+async fn test_route() {
+    let settings = |admin_token: Option<&str>| crate::serve::Settings {
+        cloud: crate::heartbeat::Cloud {
+            access_api_url: None,
+            max_clock_skew: 30,
+            timeout: 30,
+            batch_size: None,
+        },
+        retention: Default::default(),
+        admin_token: admin_token.map(str::to_string),
+        deny_untrusted_clock: false,
+    };
+    let conn = crate::db::test_conn().await;
+    let state = State::new(conn, "sqlite::memory:", settings(Some("token")))
+        .await
+        .unwrap();
+    let request = |method: Method, uri: &str, token: &str, body: &str| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(hyper::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let json = |response: Response<Body>| async {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+    };
+
+    let err = route(&state, request(Method::GET, "/api/users", "wrong", ""))
+        .await
+        .unwrap_err();
+    assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+
+    let response = route(
+        &state,
+        request(
+            Method::POST,
+            "/api/users",
+            "token",
+            r#"{"code": "123456", "positions": [1], "local": true}"#,
+        ),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let id = json(response).await["id"].as_i64().unwrap();
+
+    let response = route(&state, request(Method::GET, "/api/users", "token", ""))
+        .await
+        .unwrap();
+    let users = json(response).await;
+    assert!(users
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|u| u["id"] == id && u["code"] == "123456"));
+
+    let err = route(
+        &state,
+        request(Method::POST, "/api/users", "token", r#"{"code": 1}"#),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+    for uri in [
+        "/api/events?take=-1",
+        "/api/events?take=0",
+        "/api/events?take=1001",
+        "/api/users?take=-1",
+        "/api/points?skip=-1",
+    ] {
+        let err = route(&state, request(Method::GET, uri, "token", ""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST, "{}", uri);
+    }
+    let response = route(
+        &state,
+        request(Method::GET, "/api/events?take=1000", "token", ""),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = route(&state, request(Method::GET, "/api/hub", "token", ""))
+        .await
+        .unwrap();
+    assert_eq!(json(response).await["apiToken"], crate::logging::REDACTED);
+
+    let running = state.heartbeat.lock().await;
+    let err = route(&state, request(Method::POST, "/api/heartbeat", "token", ""))
+        .await
+        .unwrap_err();
+    assert_eq!(err.status, StatusCode::CONFLICT);
+    drop(running);
+
+    let conn = crate::db::test_conn().await;
+    let disabled = State::new(conn, "sqlite::memory:", settings(None))
+        .await
+        .unwrap();
+    let err = route(&disabled, request(Method::GET, "/api/hub", "", ""))
+        .await
+        .unwrap_err();
+    assert_eq!(err.status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,194 @@
+use crate::db;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
+use sqlx::{ConnectOptions, Connection, SqliteConnection};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const PREFIX: &str = "ahub-";
+const SUFFIX: &str = ".db";
+
+/// Take a consistent copy of the live database into dir, verify it and keep the newest
+/// `keep` backups. `vacuum into` reads the database in one transaction so writers like
+/// `access` never leave it torn. It stands in for the online backup API, which sqlx does
+/// not bind.
+pub async fn backup(dir: &str, keep: usize, database_url: &str) -> anyhow::Result<()> {
+    if keep < 1 {
+        return Err(anyhow::anyhow!(
+            "Keep at least 1 backup, or the new backup is removed right away"
+        ));
+    }
+    // An in-memory database would vacuum into another in-memory database.
+    db::database_path(database_url)?;
+    let mut conn = db::connect(database_url).await?;
+    std::fs::create_dir_all(dir)?;
+    let file = Path::new(dir).join(format!(
+        "{}{}{}",
+        PREFIX,
+        chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        SUFFIX
+    ));
+    backup_into(&file, &mut conn).await?;
+    println!("Backed up to {}", file.display());
+    for removed in rotate(Path::new(dir), keep)? {
+        println!("Removed old backup {}", removed.display());
+    }
+    Ok(())
+}
+
+/// Replace the database with a backup once it passes the integrity check and its schema
+/// matches this binary. The replaced database is kept next to it with a .pre-restore suffix.
+/// Stop commands writing to the database first.
+pub async fn restore(file: &str, database_url: &str) -> anyhow::Result<()> {
+    let path = db::database_path(database_url)?;
+    {
+        let mut conn = open_backup(Path::new(file)).await?;
+        db::integrity_check(&mut conn).await?;
+        let version = db::applied_version(&mut conn).await?;
+        if version != Some(db::schema_version()) {
+            return Err(anyhow::anyhow!(
+                "Backup schema version {} does not match expected {}",
+                version
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "none".into()),
+                db::schema_version()
+            ));
+        }
+        conn.close().await?;
+    }
+
+    // Copy next to the database first so the swap is a rename on the same filesystem.
+    let staged = PathBuf::from(format!("{}.restore", path.display()));
+    std::fs::copy(file, &staged)?;
+    if path.exists() {
+        let previous = PathBuf::from(format!("{}.pre-restore", path.display()));
+        std::fs::rename(&path, &previous)?;
+        // A stale write-ahead log would be replayed into the restored database.
+        for suffix in ["-wal", "-shm"] {
+            let log = PathBuf::from(format!("{}{}", path.display(), suffix));
+            if log.exists() {
+                std::fs::rename(&log, format!("{}{}", previous.display(), suffix))?;
+            }
+        }
+        println!("Previous database kept as {}", previous.display());
+    }
+    std::fs::rename(&staged, &path)?;
+    println!("Restored {} from {}", path.display(), file);
+    Ok(())
+}
+
+async fn backup_into(file: &Path, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    if file.exists() {
+        return Err(anyhow::anyhow!("Backup {} already exists", file.display()));
+    }
+    let partial = file.with_extension("partial");
+    // The connection may not open with create, so vacuum into an empty file.
+    std::fs::File::create(&partial)?;
+    sqlx::query(r#"vacuum into ?"#)
+        .bind(partial.to_string_lossy().as_ref())
+        .execute(&mut *conn)
+        .await?;
+
+    let result = async {
+        let mut backup = open_backup(&partial).await?;
+        db::integrity_check(&mut backup).await?;
+        backup.close().await?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+        return result;
+    }
+    std::fs::rename(&partial, file)?;
+    Ok(())
+}
+
+/// Open a backup to check it without writing to it, so its journal mode stays and no -wal
+/// or -shm files are left next to it.
+async fn open_backup(file: &Path) -> anyhow::Result<SqliteConnection> {
+    // Connecting always sets a journal mode, so set the one in the header, which byte 18
+    // has as 2 for WAL and 1 for a rollback journal like `vacuum into` writes.
+    let mut header = [0u8; 100];
+    std::fs::File::open(file)?
+        .read_exact(&mut header)
+        .map_err(|e| anyhow::anyhow!("{} is not a database: {}", file.display(), e))?;
+    let journal_mode = match header[18] {
+        2 => SqliteJournalMode::Wal,
+        _ => SqliteJournalMode::Delete,
+    };
+    Ok(SqliteConnectOptions::new()
+        .filename(file)
+        .read_only(true)
+        .journal_mode(journal_mode)
+        .connect()
+        .await?)
+}
+
+/// Remove all but the newest `keep` backups in dir. Names sort by time.
+fn rotate(dir: &Path, keep: usize) -> anyhow::Result<Vec<PathBuf>> {
+    let mut backups = Vec::<PathBuf>::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if name.starts_with(PREFIX) && name.ends_with(SUFFIX) {
+            backups.push(path);
+        }
+    }
+    backups.sort();
+    let remove = backups.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = backups.into_iter().take(remove).collect();
+    for path in removed.iter() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(removed)
+}
+
+#[tokio::test]
+async fn test_backup_and_rotate() {
+    let dir = std::env::temp_dir().join(format!("ahub-backup-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut conn = db::options(&format!("sqlite:{}", dir.join("live.db").display()))
+        .unwrap()
+        .create_if_missing(true)
+        .connect()
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&mut conn).await.unwrap();
+    for name in [
+        "ahub-20220101T000000.000Z.db",
+        "ahub-20220102T000000.000Z.db",
+    ] {
+        backup_into(&dir.join(name), &mut conn).await.unwrap();
+    }
+    assert!(
+        backup_into(&dir.join("ahub-20220102T000000.000Z.db"), &mut conn)
+            .await
+            .is_err()
+    );
+    std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+    let file = dir.join("ahub-20220102T000000.000Z.db");
+    let header = std::fs::read(&file).unwrap()[..100].to_vec();
+    let mut backup = open_backup(&file).await.unwrap();
+    assert_eq!(
+        db::applied_version(&mut backup).await.unwrap(),
+        Some(db::schema_version())
+    );
+    backup.close().await.unwrap();
+    // Checking the backup wrote nothing, not even the journal mode.
+    assert_eq!(std::fs::read(&file).unwrap()[..100], header[..]);
+    assert!(!dir.join("ahub-20220102T000000.000Z.db-wal").exists());
+    assert!(!dir.join("ahub-20220102T000000.000Z.db-shm").exists());
+
+    let err = crate::backup::backup(dir.to_str().unwrap(), 0, "sqlite::memory:")
+        .await
+        .unwrap_err();
+    assert!(err.to_string().starts_with("Keep at least 1 backup"));
+    let removed = rotate(&dir, 1).unwrap();
+    assert_eq!(removed, vec![dir.join("ahub-20220101T000000.000Z.db")]);
+    assert!(dir.join("notes.txt").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
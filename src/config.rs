@@ -0,0 +1,340 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Config file read when `--config` and AHUB_CONFIG are not given. It is optional unless a
+/// profile is selected.
+pub const DEFAULT_FILE: &str = "ahub.toml";
+
+/// Profile settings and the env var each one stands in for. A setting only takes effect
+/// when neither its command line option nor its env var is given.
+const SETTINGS: &[(&str, &str)] = &[
+    ("database-url", "DATABASE_URL"),
+    ("access-api-url", "ACCESS_API_URL"),
+    ("max-clock-skew", "MAX_CLOCK_SKEW"),
+    ("heartbeat-timeout", "HEARTBEAT_TIMEOUT"),
+    ("heartbeat-batch-size", "HEARTBEAT_BATCH_SIZE"),
+    ("heartbeat-interval", "HEARTBEAT_INTERVAL"),
+    ("listen", "AHUB_LISTEN"),
+    ("admin-token", "AHUB_ADMIN_TOKEN"),
+    ("retention-days", "RETENTION_DAYS"),
+    ("retention-rows", "RETENTION_ROWS"),
+    ("retention-vacuum", "RETENTION_VACUUM"),
+    ("deny-untrusted-clock", "DENY_UNTRUSTED_CLOCK"),
+    ("actor", "AHUB_ACTOR"),
+    ("log", "AHUB_LOG"),
+    ("log-file", "AHUB_LOG_FILE"),
+    ("log-json", "AHUB_LOG_JSON"),
+];
+
+/// Config file with named profiles:
+///
+/// ```toml
+/// profile = "dev"
+///
+/// [profiles.dev]
+/// database-url = "sqlite://db/dev.db"
+/// access-api-url = "http://localhost:3000"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Profile used when `--profile` and AHUB_PROFILE are not given
+    profile: Option<String>,
+    #[serde(default)]
+    profiles: BTreeMap<String, BTreeMap<String, toml::Value>>,
+}
+
+/// Settings of the selected profile and of `.env`, which stand in for their env vars when
+/// those are not set. They become defaults of the command line instead of env vars, so a
+/// reload can replace them without touching the environment of a running service.
+#[derive(Debug, Default)]
+pub struct Defaults {
+    /// Profile settings, then the `.env` settings the profile does not have
+    vars: Vec<(&'static str, String)>,
+    /// Settings from `.env`, which a reload keeps
+    dotenv: Vec<(&'static str, String)>,
+}
+
+/// Read `.env` and the selected profile before the command line is parsed. Precedence is
+/// command line, then env, then the profile, then `.env`, then built-in defaults, so a
+/// selected profile is not overridden by a `.env` left in the working directory. Other vars
+/// of `.env` are set in the environment as usual, so `.env` may itself select the config
+/// file and profile.
+pub fn load() -> anyhow::Result<Defaults> {
+    // The iterator is deprecated for `from_path`, which sets every var of the file.
+    #[allow(deprecated)]
+    let entries: Vec<(String, String)> = match dotenv::dotenv_iter() {
+        Ok(iter) => iter
+            .collect::<Result<_, _>>()
+            .map_err(|e| anyhow::anyhow!("Read .env: {}", e))?,
+        Err(_) => vec![],
+    };
+    let mut dotenv = Vec::<(&'static str, String)>::new();
+    for (name, value) in entries {
+        if std::env::var_os(&name).is_some() {
+            continue;
+        }
+        match SETTINGS.iter().find(|(_, var)| *var == name) {
+            Some((_, var)) => dotenv.push((var, value)),
+            None => std::env::set_var(name, value),
+        }
+    }
+    Defaults::select(dotenv)
+}
+
+impl Defaults {
+    fn select(dotenv: Vec<(&'static str, String)>) -> anyhow::Result<Self> {
+        let mut vars = selected_vars(
+            std::env::var("AHUB_CONFIG").ok(),
+            std::env::var("AHUB_PROFILE").ok(),
+        )?;
+        for (var, value) in dotenv.iter() {
+            if !vars.iter().any(|(v, _)| v == var) {
+                vars.push((var, value.clone()));
+            }
+        }
+        Ok(Self { vars, dotenv })
+    }
+
+    /// Select the profile again, as when a service is told to reload. The file is read and
+    /// checked before anything is replaced, so a broken file changes nothing.
+    pub fn reload(&self) -> anyhow::Result<Self> {
+        Self::select(self.dotenv.clone())
+    }
+
+    /// Value standing in for an env var that is not set.
+    fn get(&self, var: &str) -> Option<&str> {
+        if std::env::var_os(var).is_some() {
+            return None;
+        }
+        self.vars
+            .iter()
+            .find(|(v, _)| *v == var)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The command with the settings as default values of the options reading their env
+    /// vars. Flags take no default value, see [`Defaults::flag`].
+    pub fn command<'a>(&'a self, mut cmd: clap::Command<'a>) -> clap::Command<'a> {
+        let defaults: Vec<(&'a str, &'a str, bool)> = cmd
+            .get_arguments()
+            .filter(|a| a.is_takes_value_set())
+            .filter_map(|a| {
+                let value = self.get(a.get_env()?.to_str()?)?;
+                Some((a.get_id(), value, a.is_hide_env_values_set()))
+            })
+            .collect();
+        for (name, value, hide) in defaults {
+            // The default satisfies a required option.
+            cmd = cmd.mut_arg(name, |a| {
+                let a = a.default_value(value).required(false);
+                if hide {
+                    a.hide_default_value(true)
+                } else {
+                    a
+                }
+            });
+        }
+        for sub in cmd.get_subcommands_mut() {
+            *sub = self.command(std::mem::replace(sub, clap::Command::new("")));
+        }
+        cmd
+    }
+
+    /// A flag parsed from the command line and env, set when the setting of its env var is
+    /// true the way clap reads env vars of flags.
+    pub fn flag(&self, value: bool, var: &str) -> bool {
+        value
+            || matches!(self.get(var), Some(v)
+                if !["n", "no", "f", "false", "off", "0"].contains(&v.to_lowercase().as_str()))
+    }
+}
+
+/// Env vars of the profile selected by `--profile`, AHUB_PROFILE or the config file.
+fn selected_vars(
+    file: Option<String>,
+    profile: Option<String>,
+) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let args: Vec<String> = std::env::args().collect();
+    let file = arg_value(&args, "--config").or(file);
+    let profile = arg_value(&args, "--profile").or(profile);
+
+    let path = file.as_deref().unwrap_or(DEFAULT_FILE);
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && file.is_none() => {
+            return match profile {
+                Some(profile) => Err(anyhow::anyhow!(
+                    "Profile {} selected but config file {} does not exist",
+                    profile,
+                    path
+                )),
+                None => Ok(vec![]),
+            };
+        }
+        Err(err) => return Err(anyhow::anyhow!("Read config file {}: {}", path, err)),
+    };
+    profile_vars(&text, profile.as_deref())
+        .map_err(|e| anyhow::anyhow!("Config file {}: {}", path, e))
+}
+
+/// Value of a global option, which may come before or after the subcommand.
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    let prefix = format!("{}=", name);
+    let mut args = args.iter().skip(1).take_while(|a| *a != "--");
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Env vars and values of a profile. Without a profile the file's default profile is used,
+/// and a file without one sets nothing.
+fn profile_vars(text: &str, profile: Option<&str>) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let config: Config = toml::from_str(text)?;
+    let name = match profile.or(config.profile.as_deref()) {
+        Some(name) => name,
+        None => return Ok(vec![]),
+    };
+    let settings = config.profiles.get(name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Profile {} not found, profiles are: {}",
+            name,
+            config
+                .profiles
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    })?;
+
+    let mut vars = Vec::<(&'static str, String)>::new();
+    for (key, value) in settings {
+        let (_, var) = SETTINGS.iter().find(|(k, _)| k == key).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown setting {} in profile {}, settings are: {}",
+                key,
+                name,
+                SETTINGS
+                    .iter()
+                    .map(|(k, _)| *k)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )
+        })?;
+        let value = match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Setting {} in profile {} must be a string, integer or boolean",
+                    key,
+                    name
+                ))
+            }
+        };
+        vars.push((var, value));
+    }
+    Ok(vars)
+}
+
+#[test]
+fn test_profile_vars() {
+    let text = r#"
+        profile = "dev"
+
+        [profiles.dev]
+        database-url = "sqlite://db/dev.db"
+
+        [profiles.site-a]
+        database-url = "sqlite:///var/lib/ahub/site-a.db"
+        retention-days = 90
+        retention-vacuum = true
+        "#;
+    assert_eq!(
+        profile_vars(text, None).unwrap(),
+        vec![("DATABASE_URL", "sqlite://db/dev.db".to_string())]
+    );
+    assert_eq!(
+        profile_vars(text, Some("site-a")).unwrap(),
+        vec![
+            (
+                "DATABASE_URL",
+                "sqlite:///var/lib/ahub/site-a.db".to_string()
+            ),
+            ("RETENTION_DAYS", "90".to_string()),
+            ("RETENTION_VACUUM", "true".to_string()),
+        ]
+    );
+    assert!(profile_vars(text, Some("site-b")).is_err());
+    assert!(profile_vars("[profiles.dev]\ndatabase_url = \"x\"", Some("dev")).is_err());
+    assert!(profile_vars("[profiles.dev]\n", None).unwrap().is_empty());
+
+    let args: Vec<String> = ["ahub", "dump", "--profile=site-a", "hub", "--", "--config"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(arg_value(&args, "--profile"), Some("site-a".into()));
+    assert_eq!(arg_value(&args, "--config"), None);
+}
+
+#[test]
+fn test_defaults() {
+    let defaults = Defaults {
+        vars: vec![
+            ("HEARTBEAT_BATCH_SIZE", "500".to_string()),
+            ("AHUB_ADMIN_TOKEN", "secret".to_string()),
+            ("RETENTION_VACUUM", "true".to_string()),
+            ("DENY_UNTRUSTED_CLOCK", "off".to_string()),
+        ],
+        dotenv: vec![],
+    };
+    let cmd = || {
+        clap::Command::new("ahub").subcommand(
+            clap::Command::new("serve")
+                .arg(
+                    clap::Arg::new("batch-size")
+                        .long("batch-size")
+                        .takes_value(true)
+                        .env("HEARTBEAT_BATCH_SIZE")
+                        .required(true),
+                )
+                .arg(
+                    clap::Arg::new("admin-token")
+                        .long("admin-token")
+                        .takes_value(true)
+                        .env("AHUB_ADMIN_TOKEN")
+                        .hide_env_values(true),
+                ),
+        )
+    };
+    let matches = defaults.command(cmd()).get_matches_from(["ahub", "serve"]);
+    let serve = matches.subcommand_matches("serve").unwrap();
+    assert_eq!(serve.value_of("batch-size"), Some("500"));
+    assert_eq!(serve.value_of("admin-token"), Some("secret"));
+    let matches =
+        defaults
+            .command(cmd())
+            .get_matches_from(["ahub", "serve", "--batch-size", "100"]);
+    let serve = matches.subcommand_matches("serve").unwrap();
+    assert_eq!(serve.value_of("batch-size"), Some("100"));
+
+    let mut help = Vec::new();
+    let mut cmd = defaults.command(cmd());
+    cmd.find_subcommand_mut("serve")
+        .unwrap()
+        .write_help(&mut help)
+        .unwrap();
+    assert!(!String::from_utf8(help).unwrap().contains("secret"));
+
+    assert!(defaults.flag(false, "RETENTION_VACUUM"));
+    assert!(!defaults.flag(false, "DENY_UNTRUSTED_CLOCK"));
+    assert!(!defaults.flag(false, "AHUB_LOG_JSON"));
+}
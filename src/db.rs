@@ -0,0 +1,190 @@
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous};
+use sqlx::{ConnectOptions, SqliteConnection};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a connection waits for another to release the write lock before SQLITE_BUSY.
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Retries of a write on the access path that still failed with SQLITE_BUSY.
+pub const BUSY_RETRIES: u32 = 3;
+
+/// Options every connection opens with. WAL lets `access` read while heartbeat writes.
+pub fn options(database_url: &str) -> anyhow::Result<SqliteConnectOptions> {
+    Ok(SqliteConnectOptions::from_str(database_url)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT)
+        .foreign_keys(true))
+}
+
+/// Connect to a database whose schema is the one this binary was built with.
+pub async fn connect(database_url: &str) -> anyhow::Result<SqliteConnection> {
+    let mut conn = options(database_url)?.connect().await?;
+    check_schema(&mut conn).await?;
+    Ok(conn)
+}
+
+/// Refuse a database migrated to an older or newer schema than the embedded migrations.
+pub async fn check_schema(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let expected = schema_version();
+    match applied_version(conn).await? {
+        Some(version) if version == expected => Ok(()),
+        Some(version) if version < expected => Err(anyhow::anyhow!(
+            "Database schema version {} is older than {} expected by this ahub: run `ahub migrate`",
+            version,
+            expected
+        )),
+        Some(version) => Err(anyhow::anyhow!(
+            "Database schema version {} is newer than {} expected by this ahub: upgrade ahub",
+            version,
+            expected
+        )),
+        None => Err(anyhow::anyhow!(
+            "Database has no schema version: run `ahub migrate`"
+        )),
+    }
+}
+
+/// Whether a database error is SQLITE_BUSY or SQLITE_LOCKED, including extended codes.
+pub fn is_busy(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .map(|code| matches!(code & 0xff, 5 | 6))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Wait before retry attempt, doubling from 50ms.
+pub fn busy_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(50 << attempt.min(6))
+}
+
+/// Latest migration version embedded in the binary.
+pub fn schema_version() -> i64 {
+    sqlx::migrate!()
+        .migrations
+        .iter()
+        .map(|m| m.version)
+        .max()
+        .unwrap_or_default()
+}
+
+/// Latest migration version applied to the database. None when it was never migrated.
+pub async fn applied_version(conn: &mut SqliteConnection) -> anyhow::Result<Option<i64>> {
+    let (exists,): (bool,) = sqlx::query_as(
+        r#"select count(*) > 0 from sqlite_master where type = 'table' and name = '_sqlx_migrations'"#,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if !exists {
+        return Ok(None);
+    }
+    let (version,): (Option<i64>,) =
+        sqlx::query_as(r#"select max(version) from _sqlx_migrations where success"#)
+            .fetch_one(&mut *conn)
+            .await?;
+    Ok(version)
+}
+
+/// Run `pragma integrity_check` and fail with its messages unless it reports ok.
+pub async fn integrity_check(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let rows: Vec<(String,)> = sqlx::query_as(r#"pragma integrity_check"#)
+        .fetch_all(&mut *conn)
+        .await?;
+    if rows.len() != 1 || rows[0].0 != "ok" {
+        return Err(anyhow::anyhow!(
+            "Integrity check failed:\n{}",
+            rows.into_iter()
+                .map(|(r,)| format!("  {}", r))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// File path of a sqlite database url like sqlite://db/dev.db or sqlite:db/dev.db?mode=rwc.
+pub fn database_path(database_url: &str) -> anyhow::Result<PathBuf> {
+    let path = database_url
+        .strip_prefix("sqlite://")
+        .or_else(|| database_url.strip_prefix("sqlite:"))
+        .unwrap_or(database_url);
+    let path = path.split('?').next().unwrap_or_default();
+    if path.is_empty() || path == ":memory:" {
+        return Err(anyhow::anyhow!(
+            "Database url {} is not a file",
+            database_url
+        ));
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// Migrated in-memory database with the development hub and points.
+#[cfg(test)]
+pub async fn test_conn() -> SqliteConnection {
+    let mut conn = options("sqlite::memory:").unwrap().connect().await.unwrap();
+    sqlx::migrate!().run(&mut conn).await.unwrap();
+    sqlx::query(crate::mock::DEV_SEED)
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    conn
+}
+
+#[test]
+fn test_database_path() {
+    assert_eq!(
+        database_path("sqlite://db/dev.db").unwrap(),
+        PathBuf::from("db/dev.db")
+    );
+    assert_eq!(
+        database_path("sqlite:/var/lib/ahub/hub.db?mode=rwc").unwrap(),
+        PathBuf::from("/var/lib/ahub/hub.db")
+    );
+    assert!(database_path("sqlite::memory:").is_err());
+}
+
+#[tokio::test]
+async fn test_check_schema() {
+    let mut conn = test_conn().await;
+    assert!(check_schema(&mut conn).await.is_ok());
+
+    sqlx::query(r#"delete from _sqlx_migrations where version = ?"#)
+        .bind(schema_version())
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    let err = check_schema(&mut conn).await.unwrap_err().to_string();
+    assert!(err.contains("older"), "{}", err);
+
+    let mut conn = options("sqlite::memory:").unwrap().connect().await.unwrap();
+    assert!(check_schema(&mut conn).await.is_err());
+}
+
+#[tokio::test]
+async fn test_options() {
+    let path = std::env::temp_dir().join(format!("ahub-options-test-{}.db", std::process::id()));
+    let mut conn = options(&format!("sqlite:{}", path.display()))
+        .unwrap()
+        .create_if_missing(true)
+        .connect()
+        .await
+        .unwrap();
+    let (journal_mode, foreign_keys, busy_timeout): (String, bool, i64) = sqlx::query_as(
+        r#"select * from pragma_journal_mode, pragma_foreign_keys, pragma_busy_timeout"#,
+    )
+    .fetch_one(&mut conn)
+    .await
+    .unwrap();
+    assert_eq!(journal_mode, "wal");
+    assert!(foreign_keys);
+    assert_eq!(busy_timeout, BUSY_TIMEOUT.as_millis() as i64);
+    drop(conn);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::db;
+use crate::format::{self, Format, Tabular};
+use serde::Serialize;
+use sqlx::{Connection, SqliteConnection};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Finding {
+    check: &'static str,
+    message: String,
+    fixable: bool,
+    fixed: bool,
+}
+
+impl Finding {
+    fn new(check: &'static str, message: String) -> Self {
+        Self {
+            check,
+            message,
+            fixable: false,
+            fixed: false,
+        }
+    }
+
+    fn fixable(check: &'static str, message: String, fixed: bool) -> Self {
+        Self {
+            check,
+            message,
+            fixable: true,
+            fixed,
+        }
+    }
+}
+
+/// Check the database for corruption and inconsistent rows. With fix, rows that can be
+/// repaired without losing access or history are repaired in one transaction.
+pub async fn doctor(fix: bool, format: Format, database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::connect(database_url).await?;
+    let findings = examine(fix, &mut conn).await?;
+    format::print(format, &findings)?;
+    let remaining = findings.iter().filter(|f| !f.fixed).count();
+    if remaining > 0 {
+        return Err(anyhow::anyhow!(
+            "{} problem(s) found{}",
+            remaining,
+            if !fix && findings.iter().any(|f| f.fixable) {
+                ", run with --fix to repair the fixable ones"
+            } else {
+                ""
+            }
+        ));
+    }
+    println!("No problems found");
+    Ok(())
+}
+
+async fn examine(fix: bool, conn: &mut SqliteConnection) -> anyhow::Result<Vec<Finding>> {
+    let mut findings = Vec::<Finding>::new();
+
+    let rows: Vec<(String,)> = sqlx::query_as(r#"pragma integrity_check"#)
+        .fetch_all(&mut *conn)
+        .await?;
+    for (message,) in rows.into_iter().filter(|(r,)| r != "ok") {
+        findings.push(Finding::new("integrity", message));
+    }
+    // Nothing is written to a corrupt database.
+    let fix = fix && findings.is_empty();
+
+    // Assignments and events have their own checks below, with row details and fixes.
+    let violations: Vec<(String, String, i64)> = sqlx::query_as(
+        r#"select "table", parent, count(*) from pragma_foreign_key_check
+        where "table" not in ('AccessPointToAccessUser', 'AccessEvent')
+        group by "table", parent order by "table", parent"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    for (table, parent, count) in violations {
+        findings.push(Finding::new(
+            "foreign_key",
+            format!("{} {} rows reference missing {} rows", count, table, parent),
+        ));
+    }
+
+    let mut tx = conn.begin().await?;
+
+    let orphans: Vec<(i64, i64)> = sqlx::query_as(
+        r#"select access_point_id, access_user_id from AccessPointToAccessUser
+        where access_point_id not in (select id from AccessPoint)
+          or access_user_id not in (select id from AccessUser)
+        order by access_point_id, access_user_id"#,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for (access_point_id, access_user_id) in orphans {
+        if fix {
+            sqlx::query(
+                r#"delete from AccessPointToAccessUser where access_point_id = ? and access_user_id = ?"#,
+            )
+            .bind(access_point_id)
+            .bind(access_user_id)
+            .execute(&mut tx)
+            .await?;
+        }
+        findings.push(Finding::fixable(
+            "orphaned_assignment",
+            format!(
+                "Assignment of user {} to point {} references a missing user or point",
+                access_user_id, access_point_id
+            ),
+            fix,
+        ));
+    }
+
+    let users: Vec<(i64,)> = sqlx::query_as(
+        r#"select id from AccessUser
+        where id not in (select access_user_id from AccessPointToAccessUser) order by id"#,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for (id,) in users {
+        findings.push(Finding::new(
+            "user_without_points",
+            format!("User {} is not assigned to any point", id),
+        ));
+    }
+
+    // Heartbeat suffixes a recycled code with "-" until the user gets its new code. The
+    // suffix keeps the old code from opening doors, so it is reported and never restored.
+    let recycled: Vec<(i64, String, bool)> = sqlx::query_as(
+        r#"select id, code, local from AccessUser where code like '%-' order by id"#,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for (id, code, local) in recycled {
+        findings.push(Finding::new(
+            "recycled_code",
+            format!(
+                "User {} has recycled code {}, {}",
+                id,
+                code,
+                if local {
+                    "give it a new code with user update"
+                } else {
+                    "the next heartbeat gives it a new code"
+                }
+            ),
+        ));
+    }
+
+    let positions: Vec<(i64, i64)> = sqlx::query_as(
+        r#"select position, count(*) from AccessPoint group by position having count(*) > 1
+        order by position"#,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for (position, count) in positions {
+        findings.push(Finding::new(
+            "duplicate_position",
+            format!("{} points have position {}", count, position),
+        ));
+    }
+
+    let events: Vec<(i64, i64)> = sqlx::query_as(
+        r#"select access_point_id, count(*) from AccessEvent
+        where access_point_id not in (select id from AccessPoint)
+        group by access_point_id order by access_point_id"#,
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    for (access_point_id, count) in events {
+        findings.push(Finding::new(
+            "event_missing_point",
+            format!(
+                "{} events reference missing point {}",
+                count, access_point_id
+            ),
+        ));
+    }
+
+    // A cursor ahead of the hub clock acknowledges events the cloud never received.
+    let cursor: Option<(String, chrono::NaiveDateTime)> = sqlx::query_as(
+        r#"select id, cloud_last_access_event_at from AccessHub
+        where cloud_last_access_event_at > CURRENT_TIMESTAMP"#,
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if let Some((id, cloud_last_access_event_at)) = cursor {
+        if fix {
+            sqlx::query(r#"update AccessHub set cloud_last_access_event_at = null where id = ?"#)
+                .bind(&id)
+                .execute(&mut tx)
+                .await?;
+        }
+        findings.push(Finding::fixable(
+            "future_cursor",
+            format!(
+                "Cloud cursor {} is in the future, clearing it lets the next heartbeat take the cloud's",
+                format::timestamp(&cloud_last_access_event_at)
+            ),
+            fix,
+        ));
+    }
+
+    tx.commit().await?;
+    Ok(findings)
+}
+
+impl Tabular for Finding {
+    fn headers() -> Vec<&'static str> {
+        vec!["check", "message", "fixable", "fixed"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.check.to_string(),
+            self.message.clone(),
+            self.fixable.to_string(),
+            self.fixed.to_string(),
+        ]
+    }
+}
+
+#[tokio::test]
+async fn test_examine() {
+    let mut conn = db::test_conn().await;
+    sqlx::query(
+        r#"pragma foreign_keys = off;
+        insert into AccessUser (id, code) values (10, '111-'), (11, '222'), (12, '222-');
+        insert into AccessPointToAccessUser (access_point_id, access_user_id) values (1, 10), (1, 99), (9, 11);
+        insert into AccessPointToAccessReader (access_point_id, access_reader_id) values (9, 99);
+        insert into AccessEvent (at, access, code, access_point_id) values ('2022-01-01 00:00:00', 'deny', '1', 9);
+        update AccessHub set cloud_last_access_event_at = '2999-01-01 00:00:00';"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let checks = |findings: &[Finding]| {
+        findings
+            .iter()
+            .map(|f| format!("{} {} {}", f.check, f.fixable, f.fixed))
+            .collect::<Vec<String>>()
+    };
+
+    let findings = examine(false, &mut conn).await.unwrap();
+    assert_eq!(
+        checks(&findings),
+        vec![
+            "foreign_key false false",
+            "foreign_key false false",
+            "orphaned_assignment true false",
+            "orphaned_assignment true false",
+            "user_without_points false false",
+            "recycled_code false false",
+            "recycled_code false false",
+            "event_missing_point false false",
+            "future_cursor true false",
+        ]
+    );
+
+    examine(true, &mut conn).await.unwrap();
+    let findings = examine(false, &mut conn).await.unwrap();
+    assert_eq!(
+        checks(&findings),
+        vec![
+            "foreign_key false false",
+            "foreign_key false false",
+            "user_without_points false false",
+            "user_without_points false false",
+            "recycled_code false false",
+            "recycled_code false false",
+            "event_missing_point false false",
+        ]
+    );
+    let (code,): (String,) = sqlx::query_as(r#"select code from AccessUser where id = 10"#)
+        .fetch_one(&mut conn)
+        .await
+        .unwrap();
+    assert_eq!(code, "111-");
+}
@@ -1,8 +1,31 @@
-#[derive(Debug, sqlx::FromRow)]
+use crate::json::{json_naive_date_time, json_option_naive_date_time};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct Hub {
     pub id: String,
+    #[serde(serialize_with = "crate::json::redacted")]
     pub api_token: String,
+    #[serde(with = "json_option_naive_date_time")]
     pub cloud_last_access_event_at: Option<chrono::NaiveDateTime>,
+    pub clock_skew_seconds: Option<i64>,
+    pub clock_skew_exceeded: bool,
+}
+
+impl std::fmt::Debug for Hub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hub")
+            .field("id", &self.id)
+            .field("api_token", &crate::logging::REDACTED)
+            .field(
+                "cloud_last_access_event_at",
+                &self.cloud_last_access_event_at,
+            )
+            .field("clock_skew_seconds", &self.clock_skew_seconds)
+            .field("clock_skew_exceeded", &self.clock_skew_exceeded)
+            .finish()
+    }
 }
 
 #[derive(Debug)]
@@ -12,54 +35,142 @@ pub struct HubWithRelations {
     pub users: Vec<UserWithRelations>,
 }
 
-#[derive(Debug, Clone, sqlx::FromRow)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct Point {
     pub id: i64,
     pub position: i64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PointWithRelations {
+    #[serde(flatten)]
     pub point: Point,
     pub users: Vec<User>,
 }
 
-#[derive(Debug, sqlx::FromRow)]
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct Point2User {
     pub access_point_id: i64,
     pub access_user_id: i64,
 }
 
-#[derive(PartialEq, Clone, Debug, sqlx::FromRow)]
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Point2Reader {
+    pub access_point_id: i64,
+    pub access_reader_id: i64,
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct User {
     pub id: i64,
     pub code: String,
+    #[serde(with = "json_option_naive_date_time")]
     pub activate_code_at: Option<chrono::NaiveDateTime>,
+    #[serde(with = "json_option_naive_date_time")]
     pub expire_code_at: Option<chrono::NaiveDateTime>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct UserWithRelations {
+    #[serde(flatten)]
     pub user: User,
     pub points: Vec<Point>,
 }
 
-#[derive(Debug, Clone, sqlx::FromRow)]
+/// A networked reader. The hub keeps only a hash of its secret.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Reader {
+    pub id: i64,
+    pub name: String,
+    #[serde(with = "json_option_naive_date_time")]
+    pub last_seen_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReaderWithRelations {
+    #[serde(flatten)]
+    pub reader: Reader,
+    pub points: Vec<Point>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct Event {
     pub id: i64,
+    #[serde(with = "json_naive_date_time")]
     pub at: chrono::NaiveDateTime,
     pub access: String,
     pub code: String,
     pub access_user_id: Option<i64>,
     pub access_point_id: i64,
+    pub reason: Option<String>,
+    pub access_reader_id: Option<i64>,
 }
 
-#[derive(Debug, sqlx::FromRow)]
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
 pub struct ActiveCode {
     pub access_point_id: i64,
     pub position: i64,
     pub code: String,
     pub access_user_id: i64,
+    #[serde(with = "json_option_naive_date_time")]
     pub activate_code_at: Option<chrono::NaiveDateTime>,
+    #[serde(with = "json_option_naive_date_time")]
     pub expire_code_at: Option<chrono::NaiveDateTime>,
 }
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub kind: String,
+    pub record_id: Option<i64>,
+    pub payload: Option<String>,
+    #[serde(with = "json_naive_date_time")]
+    pub created_at: chrono::NaiveDateTime,
+    pub attempts: i64,
+    #[serde(with = "json_option_naive_date_time")]
+    pub last_attempt_at: Option<chrono::NaiveDateTime>,
+    pub last_error: Option<String>,
+    #[serde(with = "json_option_naive_date_time")]
+    pub delivered_at: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncRun {
+    pub id: i64,
+    #[serde(with = "json_naive_date_time")]
+    pub started_at: chrono::NaiveDateTime,
+    #[serde(with = "json_option_naive_date_time")]
+    pub ended_at: Option<chrono::NaiveDateTime>,
+    pub http_status: Option<i64>,
+    pub events_uploaded: i64,
+    pub users_created: i64,
+    pub users_updated: i64,
+    pub users_deleted: i64,
+    pub users_skipped: i64,
+    pub clock_skew_seconds: Option<i64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminChange {
+    pub id: i64,
+    #[serde(with = "json_naive_date_time")]
+    pub at: chrono::NaiveDateTime,
+    pub actor: String,
+    pub action: String,
+    pub record_id: Option<i64>,
+    pub detail: Option<String>,
+}
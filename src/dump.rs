@@ -1,213 +1,498 @@
 use crate::domain::{
-    ActiveCode, Event, Hub, Point, Point2User, PointWithRelations, User, UserWithRelations,
+    ActiveCode, AdminChange, Event, Hub, OutboxEntry, Point, Point2User, PointWithRelations,
+    Reader, ReaderWithRelations, SyncRun, User, UserWithRelations,
 };
+use crate::events::{self, EventFilter};
+use crate::format::{self, Format, Tabular};
 use futures::TryStreamExt;
+use serde::Serialize;
 use sqlx::SqliteConnection;
 use std::collections::HashMap;
 
-pub async fn dump_hub(conn: &mut SqliteConnection) -> anyhow::Result<()> {
-    let hub: Hub =
-        sqlx::query_as("select id, api_token, cloud_last_access_event_at from AccessHub")
-            .fetch_one(conn)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SqliteVersion {
+    sqlite_version: String,
+}
+
+impl Tabular for SqliteVersion {
+    fn headers() -> Vec<&'static str> {
+        vec!["sqliteVersion"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![self.sqlite_version.clone()]
+    }
+}
+
+pub async fn dump_hub(format: Format, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    format::print(format, &[select_hub(conn).await?])
+}
+
+pub async fn select_hub(conn: &mut SqliteConnection) -> anyhow::Result<Hub> {
+    let hub: Option<Hub> =
+        sqlx::query_as("select id, api_token, cloud_last_access_event_at, clock_skew_seconds, clock_skew_exceeded from AccessHub")
+            .fetch_optional(conn)
             .await?;
-    println!("{:#?}", hub);
-    Ok(())
+    hub.ok_or_else(|| {
+        anyhow::anyhow!(
+            "Hub is not provisioned: run `ahub token --hub-id <id> --set <token>` with the cloud's hub id and token"
+        )
+    })
 }
 
-pub async fn dump_sqlite_version(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+pub async fn dump_sqlite_version(
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
     let sqlite_version: (String,) = sqlx::query_as("select sqlite_version()")
         .fetch_one(conn)
         .await?;
-    println!("sqlite_version: {}", sqlite_version.0);
+    if format == Format::Debug {
+        println!("sqlite_version: {}", sqlite_version.0);
+        return Ok(());
+    }
+    format::print(
+        format,
+        &[SqliteVersion {
+            sqlite_version: sqlite_version.0,
+        }],
+    )
+}
+
+pub async fn dump_events(
+    filter: &EventFilter,
+    take: i32,
+    before: Option<i64>,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let events = events::select_events(filter, before, take, &mut *conn).await?;
+    format::print(format, &events)?;
+    if events.len() == take as usize {
+        if let Some(last) = events.last() {
+            eprintln!("next page: --before {}", last.id);
+        }
+    }
     Ok(())
 }
 
-pub async fn dump_events(take: i32, skip: i32, conn: &mut SqliteConnection) -> anyhow::Result<()> {
-    let events = sqlx::query_as::<_, Event>(
-        r#"
-        select id, at, access, code, access_user_id, access_point_id from AccessEvent order by at desc limit ? offset ?
-        "#
+/// Print the latest take events oldest first, then poll for events inserted by any process
+/// until interrupted.
+pub async fn follow_events(
+    filter: &EventFilter,
+    take: i32,
+    interval: std::time::Duration,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let (events, mut last_id) = first_page(filter, take, &mut *conn).await?;
+    format::print_batch(format, &events, true)?;
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+        let events = events::select_events_after(filter, last_id, 100, &mut *conn).await?;
+        format::print_batch(format, &events, false)?;
+        if let Some(last) = events.last() {
+            last_id = last.id;
+        }
+    }
+    Ok(())
+}
+
+/// The newest `take` events, oldest first, and the id to follow from. The id is read
+/// before the page, so an event inserted in between is on the page or after the id.
+async fn first_page(
+    filter: &EventFilter,
+    take: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<(Vec<Event>, i64)> {
+    let (max_id,): (i64,) = sqlx::query_as("select coalesce(max(id), 0) from AccessEvent")
+        .fetch_one(&mut *conn)
+        .await?;
+    let mut events = events::select_events(filter, None, take, &mut *conn).await?;
+    events.reverse();
+    let last_id = events.last().map_or(max_id, |e| e.id.max(max_id));
+    Ok((events, last_id))
+}
+
+pub async fn dump_users(
+    take: i32,
+    skip: i32,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    format::print(format, &select_users(take, skip, conn).await?)
+}
+
+/// Users with their points, by id.
+pub async fn select_users(
+    take: i32,
+    skip: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<UserWithRelations>> {
+    let users = sqlx::query_as::<_, User>(
+        r#"select id, code, activate_code_at, expire_code_at from AccessUser order by id asc limit ? offset ?"#,
     )
     .bind(take)
     .bind(skip)
     .fetch_all(&mut *conn)
     .await?;
 
-    for e in events {
-        println!("{:#?}", e);
-    }
-    Ok(())
+    load_users_with_points(users, &mut *conn).await
 }
 
-pub async fn dump_users(take: i32, skip: i32, conn: &mut SqliteConnection) -> anyhow::Result<()> {
-    let users = sqlx::query_as::<_, User>(
-        r#"select id, code, activate_code_at, expire_code_at from AccessUser order by id asc limit ? offset ?"#,
+pub async fn dump_points(
+    take: i32,
+    skip: i32,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    format::print(format, &select_points(take, skip, conn).await?)
+}
+
+/// Points with their users, by position.
+pub async fn select_points(
+    take: i32,
+    skip: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<PointWithRelations>> {
+    let points = sqlx::query_as::<_, Point>(
+        r#"select id, position from AccessPoint order by position asc limit ? offset ?"#,
     )
     .bind(take)
     .bind(skip)
     .fetch_all(&mut *conn)
     .await?;
 
+    load_points_with_users(points, &mut *conn).await
+}
+
+pub async fn load_users_with_points(
+    users: Vec<User>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<UserWithRelations>> {
     let user_ids: Vec<i64> = users.iter().map(|u| u.id).collect();
+    let user2points = load_point2user(Side::User, &user_ids, &mut *conn).await?;
+    let point_ids: Vec<i64> = user2points.values().flatten().copied().collect();
+    let points = load_by_ids::<Point>(
+        "select id, position from AccessPoint",
+        &point_ids,
+        |p| p.id,
+        &mut *conn,
+    )
+    .await?;
+
+    Ok(users
+        .into_iter()
+        .map(|u| {
+            let points = related(&user2points, u.id, &points);
+            UserWithRelations { user: u, points }
+        })
+        .collect())
+}
+
+pub async fn load_points_with_users(
+    points: Vec<Point>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<PointWithRelations>> {
+    let point_ids: Vec<i64> = points.iter().map(|p| p.id).collect();
+    let point2users = load_point2user(Side::Point, &point_ids, &mut *conn).await?;
+    let user_ids: Vec<i64> = point2users.values().flatten().copied().collect();
+    let users = load_by_ids::<User>(
+        "select id, code, activate_code_at, expire_code_at from AccessUser",
+        &user_ids,
+        |u| u.id,
+        &mut *conn,
+    )
+    .await?;
+
+    Ok(points
+        .into_iter()
+        .map(|p| {
+            let users = related(&point2users, p.id, &users);
+            PointWithRelations { point: p, users }
+        })
+        .collect())
+}
+
+/// Side of AccessPointToAccessUser to group by.
+#[derive(Clone, Copy)]
+enum Side {
+    Point,
+    User,
+}
+
+/// Ids on the other side of AccessPointToAccessUser for each of ids on side.
+async fn load_point2user(
+    side: Side,
+    ids: &[i64],
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<HashMap<i64, Vec<i64>>> {
+    let column = match side {
+        Side::Point => "access_point_id",
+        Side::User => "access_user_id",
+    };
     let query = format!(
-        "select access_user_id, access_point_id from AccessPointToAccessUser where access_user_id in ({})",
-        user_ids
-            .iter()
-            .map(|_| "?")
-            .collect::<Vec<&str>>()
-            .join(", ")
+        "select access_point_id, access_user_id from AccessPointToAccessUser where {} in ({}) order by access_point_id, access_user_id",
+        column,
+        ids.iter().map(|_| "?").collect::<Vec<&str>>().join(", ")
     );
     let mut q = sqlx::query_as::<_, Point2User>(&query);
-    for id in user_ids.iter() {
+    for id in ids.iter() {
         q = q.bind(id);
     }
 
-    let mut user2points = HashMap::<i64, Vec<i64>>::new();
-    {
-        let mut rows = q.fetch(&mut *conn);
-        while let Some(u2p) = rows.try_next().await? {
-            if let Some(points) = user2points.get_mut(&u2p.access_user_id) {
-                points.push(u2p.access_point_id);
-            } else {
-                user2points.insert(u2p.access_user_id, vec![u2p.access_point_id]);
-            }
-        }
+    let mut relation = HashMap::<i64, Vec<i64>>::new();
+    let mut rows = q.fetch(&mut *conn);
+    while let Some(p2u) = rows.try_next().await? {
+        let (key, other) = match side {
+            Side::Point => (p2u.access_point_id, p2u.access_user_id),
+            Side::User => (p2u.access_user_id, p2u.access_point_id),
+        };
+        relation.entry(key).or_default().push(other);
     }
-    let point_ids: Vec<_> = user2points.values().flatten().copied().collect();
+    Ok(relation)
+}
 
+async fn load_by_ids<T>(
+    select: &str,
+    ids: &[i64],
+    id: fn(&T) -> i64,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<HashMap<i64, T>>
+where
+    T: for<'r> sqlx::FromRow<'r, sqlx::sqlite::SqliteRow> + Send + Unpin,
+{
     let query = format!(
-        "select id, position from AccessPoint where id in ({})",
-        point_ids
-            .iter()
-            .map(|_| "?")
-            .collect::<Vec<&str>>()
-            .join(", ")
+        "{} where id in ({})",
+        select,
+        ids.iter().map(|_| "?").collect::<Vec<&str>>().join(", ")
     );
-    let mut q = sqlx::query_as::<_, Point>(&query);
-    for id in point_ids.iter() {
+    let mut q = sqlx::query_as::<_, T>(&query);
+    for id in ids.iter() {
         q = q.bind(id);
     }
-    let mut points = HashMap::<i64, Point>::new();
-    {
-        let mut rows = q.fetch(&mut *conn);
-        while let Some(p) = rows.try_next().await? {
-            points.insert(p.id, p);
-        }
+    let mut records = HashMap::<i64, T>::new();
+    let mut rows = q.fetch(&mut *conn);
+    while let Some(r) = rows.try_next().await? {
+        records.insert(id(&r), r);
     }
+    Ok(records)
+}
 
-    let users: Vec<UserWithRelations> = users
+fn related<T: Clone>(
+    relation: &HashMap<i64, Vec<i64>>,
+    id: i64,
+    records: &HashMap<i64, T>,
+) -> Vec<T> {
+    match relation.get(&id) {
+        Some(ids) => ids.iter().flat_map(|id| records.get(id)).cloned().collect(),
+        None => vec![],
+    }
+}
+
+pub async fn dump_codes(format: Format, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    format::print(format, &select_codes(conn).await?)
+}
+
+pub async fn select_codes(conn: &mut SqliteConnection) -> anyhow::Result<Vec<ActiveCode>> {
+    let codes = sqlx::query_as::<_, ActiveCode>(
+        r#"select access_point_id, position, code, access_user_id, activate_code_at, expire_code_at
+        from ActiveCode"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(codes)
+}
+
+pub async fn dump_readers(format: Format, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    format::print(format, &select_readers(conn).await?)
+}
+
+/// Readers with the points they may ask for, by name.
+pub async fn select_readers(
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<ReaderWithRelations>> {
+    let readers = sqlx::query_as::<_, Reader>(
+        r#"select id, name, last_seen_at from AccessReader order by name asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let rows: Vec<(i64, i64, i64)> = sqlx::query_as(
+        r#"select r.access_reader_id, p.id, p.position
+        from AccessPointToAccessReader r join AccessPoint p on r.access_point_id = p.id
+        order by p.position asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(readers
         .into_iter()
-        .map(|u| {
-            let id = u.id;
-            UserWithRelations {
-                user: u,
-                points: match user2points.get(&id) {
-                    Some(point_ids) => point_ids
-                        .iter()
-                        .flat_map(|id| points.get(id))
-                        .cloned()
-                        .collect(),
-                    None => vec![],
-                },
-            }
+        .map(|reader| {
+            let points = rows
+                .iter()
+                .filter(|(reader_id, _, _)| *reader_id == reader.id)
+                .map(|(_, id, position)| Point {
+                    id: *id,
+                    position: *position,
+                })
+                .collect();
+            ReaderWithRelations { reader, points }
         })
-        .collect();
+        .collect())
+}
 
-    for u in &users {
-        println!("{:#?}\n", u)
+pub async fn dump_outbox(
+    take: i32,
+    skip: i32,
+    all: bool,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let pending: Vec<(String, i64)> = sqlx::query_as(
+        r#"select kind, count(*) from Outbox where delivered_at is null group by kind order by kind asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    if format == Format::Debug {
+        println!("pending {:#?}", pending);
     }
 
-    Ok(())
+    let entries = sqlx::query_as::<_, OutboxEntry>(
+        r#"select id, kind, record_id, payload, created_at, attempts, last_attempt_at, last_error, delivered_at
+        from Outbox where ? or delivered_at is null order by id asc limit ? offset ?"#,
+    )
+    .bind(all)
+    .bind(take)
+    .bind(skip)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    format::print(format, &entries)
 }
 
-pub async fn dump_points(take: i32, skip: i32, conn: &mut SqliteConnection) -> anyhow::Result<()> {
-    let points = sqlx::query_as::<_, Point>(
-        r#"select id, position from AccessPOint order by position asc limit ? offset ?"#,
+pub async fn dump_syncs(
+    take: i32,
+    skip: i32,
+    failed: bool,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let runs = sqlx::query_as::<_, SyncRun>(
+        r#"select id, started_at, ended_at, http_status, events_uploaded, users_created, users_updated,
+        users_deleted, users_skipped, clock_skew_seconds, error from SyncRun where not ? or error is not null order by id desc limit ? offset ?"#,
     )
+    .bind(failed)
     .bind(take)
     .bind(skip)
     .fetch_all(&mut *conn)
     .await?;
 
-    let point_ids: Vec<i64> = points.iter().map(|p| p.id).collect();
-    let query = format!(
-        "select access_point_id, access_user_id from AccessPointToAccessUser where access_point_id in ({})",
-        point_ids
-            .iter()
-            .map(|_| "?")
-            .collect::<Vec<&str>>()
-            .join(",")
-    );
-    let mut q = sqlx::query_as::<_, Point2User>(&query);
-    for id in point_ids.iter() {
-        q = q.bind(id);
-    }
+    format::print(format, &runs)
+}
 
-    let mut point2users = HashMap::<i64, Vec<i64>>::new();
-    {
-        let mut rows = q.fetch(&mut *conn);
-        while let Some(p2u) = rows.try_next().await? {
-            if let Some(points) = point2users.get_mut(&p2u.access_user_id) {
-                points.push(p2u.access_point_id);
-            } else {
-                point2users.insert(p2u.access_user_id, vec![p2u.access_point_id]);
-            }
-        }
-    }
+pub async fn dump_changes(
+    take: i32,
+    skip: i32,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let changes = sqlx::query_as::<_, AdminChange>(
+        r#"select id, at, actor, action, record_id, detail from AdminChange order by id desc limit ? offset ?"#,
+    )
+    .bind(take)
+    .bind(skip)
+    .fetch_all(&mut *conn)
+    .await?;
 
-    let point_ids: Vec<_> = point2users.values().flatten().copied().collect();
+    format::print(format, &changes)
+}
 
-    let query = format!(
-        "select id, code, activate_code_at, expire_code_at from AccessUser where id in ({})",
-        point_ids
-            .iter()
-            .map(|_| "?")
-            .collect::<Vec<&str>>()
-            .join(", ")
-    );
-    let mut q = sqlx::query_as::<_, User>(&query);
-    for id in point_ids.iter() {
-        q = q.bind(id);
-    }
-    let mut users = HashMap::<i64, User>::new();
-    {
-        let mut rows = q.fetch(&mut *conn);
-        while let Some(u) = rows.try_next().await? {
-            users.insert(u.id, u);
-        }
-    }
+#[cfg(test)]
+async fn fixture_conn() -> SqliteConnection {
+    let mut conn = crate::db::test_conn().await;
+    // User ids differ from point ids so a relation grouped by the wrong side shows up.
+    sqlx::query(
+        r#"insert into AccessUser (id, code) values (10, '111'), (11, '222'), (12, '333');
+        insert into AccessPointToAccessUser (access_point_id, access_user_id) values (1, 10), (2, 10), (2, 11), (4, 12);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    conn
+}
 
-    let points: Vec<PointWithRelations> = points
-        .into_iter()
-        .map(|p| {
-            let id = p.id;
-            PointWithRelations {
-                point: p,
-                users: match point2users.get(&id) {
-                    Some(user_ids) => user_ids
-                        .iter()
-                        .flat_map(|id| users.get(id))
-                        .cloned()
-                        .collect(),
-                    None => vec![],
-                },
-            }
-        })
+#[tokio::test]
+async fn test_load_points_with_users() {
+    let mut conn = fixture_conn().await;
+    let points =
+        sqlx::query_as::<_, Point>("select id, position from AccessPoint order by position")
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    let points = load_points_with_users(points, &mut conn).await.unwrap();
+    let point2users: Vec<(i64, Vec<i64>)> = points
+        .iter()
+        .map(|p| (p.point.id, p.users.iter().map(|u| u.id).collect()))
         .collect();
+    assert_eq!(
+        point2users,
+        vec![(1, vec![10]), (2, vec![10, 11]), (3, vec![]), (4, vec![12])]
+    );
+}
 
-    println!("points {:#?}", points);
-
-    Ok(())
+#[tokio::test]
+async fn test_load_users_with_points() {
+    let mut conn = fixture_conn().await;
+    let users = sqlx::query_as::<_, User>(
+        "select id, code, activate_code_at, expire_code_at from AccessUser order by id",
+    )
+    .fetch_all(&mut conn)
+    .await
+    .unwrap();
+    let users = load_users_with_points(users, &mut conn).await.unwrap();
+    let user2points: Vec<(i64, Vec<i64>)> = users
+        .iter()
+        .map(|u| (u.user.id, u.points.iter().map(|p| p.position).collect()))
+        .collect();
+    assert_eq!(
+        user2points,
+        vec![(10, vec![1, 2]), (11, vec![2]), (12, vec![4])]
+    );
 }
 
-pub async fn dump_codes(conn: &mut SqliteConnection) -> anyhow::Result<()> {
-    let codes = sqlx::query_as::<_, ActiveCode>(
-        r#"select access_point_id, position, code, access_user_id, activate_code_at, expire_code_at
-        from ActiveCode"#,
+#[tokio::test]
+async fn test_first_page() {
+    let mut conn = fixture_conn().await;
+    sqlx::query(
+        r#"insert into AccessEvent (id, at, access, code, access_user_id, access_point_id)
+        values (1, '2022-04-01 00:00:00', 'grant', '111', 10, 1),
+          (2, '2022-04-01 00:00:01', 'grant', '111', 10, 1),
+          (3, '2022-04-01 00:00:02', 'deny', '999', null, 2);"#,
     )
-    .fetch_all(&mut *conn)
-    .await?;
-    println!("codes {:#?}", codes);
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let filter = EventFilter {
+        access: Some("grant".into()),
+        ..Default::default()
+    };
+    let (events, last_id) = first_page(&filter, 1, &mut conn).await.unwrap();
+    assert_eq!(events.iter().map(|e| e.id).collect::<Vec<i64>>(), vec![2]);
+    // Events the filter leaves out are not read again.
+    assert_eq!(last_id, 3);
 
-    Ok(())
+    sqlx::query(
+        r#"insert into AccessEvent (id, at, access, code, access_user_id, access_point_id)
+        values (4, '2022-04-01 00:00:03', 'grant', '111', 10, 1);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let events = events::select_events_after(&filter, last_id, 100, &mut conn)
+        .await
+        .unwrap();
+    assert_eq!(events.iter().map(|e| e.id).collect::<Vec<i64>>(), vec![4]);
 }
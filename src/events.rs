@@ -0,0 +1,109 @@
+use crate::domain::Event;
+use crate::format::parse_timestamp;
+use sqlx::query::QueryAs;
+use sqlx::sqlite::SqliteArguments;
+use sqlx::{Sqlite, SqliteConnection};
+
+/// Filters shared by commands that query access events.
+#[derive(clap::Args, Debug, Default)]
+pub struct EventFilter {
+    /// Events at or after, UTC (2022-03-25, 2022-03-25T17:03:01.000Z)
+    #[clap(long, parse(try_from_str = parse_timestamp))]
+    pub since: Option<chrono::NaiveDateTime>,
+
+    /// Events before, UTC (2022-03-25, 2022-03-25T17:03:01.000Z)
+    #[clap(long, parse(try_from_str = parse_timestamp))]
+    pub until: Option<chrono::NaiveDateTime>,
+
+    /// Point position (1-based)
+    #[clap(long, parse(try_from_str))]
+    pub position: Option<i64>,
+
+    /// User id
+    #[clap(long, parse(try_from_str))]
+    pub user: Option<i64>,
+
+    /// Code
+    #[clap(long)]
+    pub code: Option<String>,
+
+    /// Access
+    #[clap(long, possible_values = ["grant", "deny"])]
+    pub access: Option<String>,
+
+    /// Deny reason
+    #[clap(long)]
+    pub reason: Option<String>,
+
+    /// Reader id
+    #[clap(long, parse(try_from_str))]
+    pub reader: Option<i64>,
+}
+
+/// Filter conditions on AccessEvent, bound as ?1 through ?8 by bind_filter.
+pub const FILTER_CONDITIONS: &str = r#"(?1 is null or at >= ?1) and (?2 is null or at < ?2)
+    and (?3 is null or access_point_id in (select id from AccessPoint where position = ?3))
+    and (?4 is null or access_user_id = ?4) and (?5 is null or code = ?5)
+    and (?6 is null or access = ?6) and (?7 is null or reason = ?7)
+    and (?8 is null or access_reader_id = ?8)"#;
+
+/// Events matching filter, newest first. Before is the id cursor from the previous page.
+pub async fn select_events(
+    filter: &EventFilter,
+    before: Option<i64>,
+    take: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<Event>> {
+    select(
+        filter,
+        "(?9 is null or id < ?9) order by id desc",
+        before,
+        take,
+        conn,
+    )
+    .await
+}
+
+/// Events matching filter inserted after the event with id after, oldest first.
+pub async fn select_events_after(
+    filter: &EventFilter,
+    after: i64,
+    take: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<Event>> {
+    select(filter, "id > ?9 order by id asc", Some(after), take, conn).await
+}
+
+pub fn bind_filter<'q, O>(
+    q: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+    filter: &'q EventFilter,
+) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+    q.bind(filter.since)
+        .bind(filter.until)
+        .bind(filter.position)
+        .bind(filter.user)
+        .bind(&filter.code)
+        .bind(&filter.access)
+        .bind(&filter.reason)
+        .bind(filter.reader)
+}
+
+async fn select(
+    filter: &EventFilter,
+    cursor_condition: &str,
+    cursor: Option<i64>,
+    take: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<Event>> {
+    let query = format!(
+        r#"select id, at, access, code, access_user_id, access_point_id, reason, access_reader_id from AccessEvent
+        where {} and {} limit ?10"#,
+        FILTER_CONDITIONS, cursor_condition
+    );
+    let events = bind_filter(sqlx::query_as::<_, Event>(&query), filter)
+        .bind(cursor)
+        .bind(take)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(events)
+}
@@ -0,0 +1,397 @@
+use crate::domain::{
+    ActiveCode, AdminChange, Event, Hub, OutboxEntry, Point, PointWithRelations,
+    ReaderWithRelations, SyncRun, User, UserWithRelations,
+};
+use serde::Serialize;
+
+/// Output format of dump commands.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Rust debug output
+    Debug,
+    /// JSON array
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// CSV with header
+    Csv,
+    /// Aligned columns with header
+    Table,
+}
+
+/// Flat columns of a record for csv and table output.
+pub trait Tabular {
+    fn headers() -> Vec<&'static str>;
+    fn row(&self) -> Vec<String>;
+}
+
+pub fn print<T: std::fmt::Debug + Serialize + Tabular>(
+    format: Format,
+    records: &[T],
+) -> anyhow::Result<()> {
+    print_records(format, records, true)
+}
+
+/// Print one batch of a stream of records. Header is only printed with the first batch and
+/// json is written as ndjson since the array never ends.
+pub fn print_batch<T: std::fmt::Debug + Serialize + Tabular>(
+    format: Format,
+    records: &[T],
+    first: bool,
+) -> anyhow::Result<()> {
+    let format = match format {
+        Format::Json => Format::Ndjson,
+        format => format,
+    };
+    print_records(format, records, first)
+}
+
+fn print_records<T: std::fmt::Debug + Serialize + Tabular>(
+    format: Format,
+    records: &[T],
+    header: bool,
+) -> anyhow::Result<()> {
+    match format {
+        Format::Debug => {
+            for r in records {
+                println!("{:#?}", r);
+            }
+        }
+        Format::Json => println!("{}", serde_json::to_string_pretty(records)?),
+        Format::Ndjson => {
+            for r in records {
+                println!("{}", serde_json::to_string(r)?);
+            }
+        }
+        Format::Csv => {
+            let mut w = csv::Writer::from_writer(std::io::stdout());
+            if header {
+                w.write_record(T::headers())?;
+            }
+            for r in records {
+                w.write_record(r.row())?;
+            }
+            w.flush()?;
+        }
+        Format::Table => {
+            let rows: Vec<Vec<String>> = records.iter().map(|r| r.row()).collect();
+            let headers = T::headers();
+            let s = table(&headers, &rows);
+            if header {
+                print!("{}", s);
+            } else {
+                print!(
+                    "{}",
+                    s.split_once('\n').map(|(_, rows)| rows).unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<width$}", c, width = *w))
+            .collect();
+        format!("{}\n", cells.join("  ").trim_end())
+    };
+    let mut s = line(headers.to_vec());
+    for row in rows {
+        s.push_str(&line(row.iter().map(|c| c.as_str()).collect()));
+    }
+    s
+}
+
+/// ISO-8601 like the heartbeat JSON (JS Date.toJSON()).
+pub fn timestamp(dt: &chrono::NaiveDateTime) -> String {
+    dt.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// Parse a UTC timestamp from the command line: ISO-8601 like the heartbeat JSON, SQLite's
+/// "YYYY-MM-DD HH:MM:SS" or a bare date.
+pub fn parse_timestamp(s: &str) -> Result<chrono::NaiveDateTime, String> {
+    if let Ok(dt) = s.parse::<chrono::DateTime<chrono::Utc>>() {
+        return Ok(dt.naive_utc());
+    }
+    if let Ok(dt) = s.parse::<chrono::NaiveDateTime>() {
+        return Ok(dt);
+    }
+    if let Ok(dt) = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S") {
+        return Ok(dt);
+    }
+    s.parse::<chrono::NaiveDate>()
+        .map(|d| d.and_hms(0, 0, 0))
+        .map_err(|_| format!("Invalid timestamp {}", s))
+}
+
+fn option_timestamp(dt: &Option<chrono::NaiveDateTime>) -> String {
+    dt.as_ref().map(timestamp).unwrap_or_default()
+}
+
+fn option<T: ToString>(v: &Option<T>) -> String {
+    v.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn join<T: ToString>(v: impl Iterator<Item = T>) -> String {
+    v.map(|v| v.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+impl Tabular for Hub {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "id",
+            "apiToken",
+            "cloudLastAccessEventAt",
+            "clockSkewSeconds",
+            "clockSkewExceeded",
+        ]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            crate::logging::REDACTED.to_string(),
+            option_timestamp(&self.cloud_last_access_event_at),
+            option(&self.clock_skew_seconds),
+            self.clock_skew_exceeded.to_string(),
+        ]
+    }
+}
+
+impl Tabular for Point {
+    fn headers() -> Vec<&'static str> {
+        vec!["id", "position"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![self.id.to_string(), self.position.to_string()]
+    }
+}
+
+impl Tabular for PointWithRelations {
+    fn headers() -> Vec<&'static str> {
+        vec!["id", "position", "userIds"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.point.id.to_string(),
+            self.point.position.to_string(),
+            join(self.users.iter().map(|u| u.id)),
+        ]
+    }
+}
+
+impl Tabular for User {
+    fn headers() -> Vec<&'static str> {
+        vec!["id", "code", "activateCodeAt", "expireCodeAt"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.code.clone(),
+            option_timestamp(&self.activate_code_at),
+            option_timestamp(&self.expire_code_at),
+        ]
+    }
+}
+
+impl Tabular for UserWithRelations {
+    fn headers() -> Vec<&'static str> {
+        let mut headers = User::headers();
+        headers.push("positions");
+        headers
+    }
+    fn row(&self) -> Vec<String> {
+        let mut row = self.user.row();
+        row.push(join(self.points.iter().map(|p| p.position)));
+        row
+    }
+}
+
+impl Tabular for ReaderWithRelations {
+    fn headers() -> Vec<&'static str> {
+        vec!["id", "name", "lastSeenAt", "positions"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.reader.id.to_string(),
+            self.reader.name.clone(),
+            option_timestamp(&self.reader.last_seen_at),
+            join(self.points.iter().map(|p| p.position)),
+        ]
+    }
+}
+
+impl Tabular for Event {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "id",
+            "at",
+            "access",
+            "code",
+            "accessUserId",
+            "accessPointId",
+            "reason",
+            "accessReaderId",
+        ]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            timestamp(&self.at),
+            self.access.clone(),
+            self.code.clone(),
+            option(&self.access_user_id),
+            self.access_point_id.to_string(),
+            option(&self.reason),
+            option(&self.access_reader_id),
+        ]
+    }
+}
+
+impl Tabular for ActiveCode {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "accessPointId",
+            "position",
+            "code",
+            "accessUserId",
+            "activateCodeAt",
+            "expireCodeAt",
+        ]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.access_point_id.to_string(),
+            self.position.to_string(),
+            self.code.clone(),
+            self.access_user_id.to_string(),
+            option_timestamp(&self.activate_code_at),
+            option_timestamp(&self.expire_code_at),
+        ]
+    }
+}
+
+impl Tabular for OutboxEntry {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "id",
+            "kind",
+            "recordId",
+            "payload",
+            "createdAt",
+            "attempts",
+            "lastAttemptAt",
+            "lastError",
+            "deliveredAt",
+        ]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            self.kind.clone(),
+            option(&self.record_id),
+            option(&self.payload),
+            timestamp(&self.created_at),
+            self.attempts.to_string(),
+            option_timestamp(&self.last_attempt_at),
+            option(&self.last_error),
+            option_timestamp(&self.delivered_at),
+        ]
+    }
+}
+
+impl Tabular for SyncRun {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "id",
+            "startedAt",
+            "endedAt",
+            "httpStatus",
+            "eventsUploaded",
+            "usersCreated",
+            "usersUpdated",
+            "usersDeleted",
+            "usersSkipped",
+            "clockSkewSeconds",
+            "error",
+        ]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            timestamp(&self.started_at),
+            option_timestamp(&self.ended_at),
+            option(&self.http_status),
+            self.events_uploaded.to_string(),
+            self.users_created.to_string(),
+            self.users_updated.to_string(),
+            self.users_deleted.to_string(),
+            self.users_skipped.to_string(),
+            option(&self.clock_skew_seconds),
+            option(&self.error),
+        ]
+    }
+}
+
+impl Tabular for AdminChange {
+    fn headers() -> Vec<&'static str> {
+        vec!["id", "at", "actor", "action", "recordId", "detail"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.to_string(),
+            timestamp(&self.at),
+            self.actor.clone(),
+            self.action.clone(),
+            option(&self.record_id),
+            option(&self.detail),
+        ]
+    }
+}
+
+#[test]
+fn test_table() {
+    let rows = vec![
+        vec!["1".to_string(), "grant".to_string()],
+        vec!["10".to_string(), "".to_string()],
+    ];
+    assert_eq!(
+        table(&["id", "access"], &rows),
+        "id  access\n1   grant\n10\n"
+    );
+}
+
+#[test]
+fn test_hub_redacted() {
+    let hub = Hub {
+        id: "hub".into(),
+        api_token: "secret".into(),
+        cloud_last_access_event_at: None,
+        clock_skew_seconds: None,
+        clock_skew_exceeded: false,
+    };
+    assert!(!serde_json::to_string(&hub).unwrap().contains("secret"));
+    assert!(!hub.row().contains(&"secret".to_string()));
+}
+
+#[test]
+fn test_parse_timestamp() {
+    let dt = chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(1, 46, 40);
+    assert_eq!(parse_timestamp("2001-09-08T01:46:40.000Z"), Ok(dt));
+    assert_eq!(parse_timestamp("2001-09-08T01:46:40"), Ok(dt));
+    assert_eq!(parse_timestamp("2001-09-08 01:46:40"), Ok(dt));
+    assert_eq!(
+        parse_timestamp("2001-09-08"),
+        Ok(chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(0, 0, 0))
+    );
+    assert!(parse_timestamp("09/08/2001").is_err());
+}
@@ -1,7 +1,12 @@
+use crate::db;
 use crate::domain::{Hub, Point, Point2User, User};
+use crate::json::{json_naive_date_time, json_option_naive_date_time};
+use crate::metrics;
+use crate::outbox;
 use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use tracing::{debug, info, trace, warn};
 // use anyhow::Context;
 use sqlx::{Connection, SqliteConnection};
 
@@ -11,7 +16,7 @@ struct RequestData {
     access_hub: AccessHubRequestData,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct AccessHubRequestData {
     id: String,
@@ -19,17 +24,69 @@ struct AccessHubRequestData {
     #[serde(with = "json_option_naive_date_time")]
     cloud_last_access_event_at: Option<chrono::NaiveDateTime>,
     access_events: Vec<AccessEventRequestData>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    access_alerts: Vec<serde_json::Value>,
+    /// Readers are managed on the hub, so the cloud gets them on every heartbeat. Their
+    /// secrets never leave the hub.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    access_readers: Vec<AccessReaderRequestData>,
+}
+
+impl std::fmt::Debug for AccessHubRequestData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessHubRequestData")
+            .field("id", &self.id)
+            .field("api_token", &crate::logging::REDACTED)
+            .field(
+                "cloud_last_access_event_at",
+                &self.cloud_last_access_event_at,
+            )
+            .field("access_events", &self.access_events)
+            .field("access_alerts", &self.access_alerts)
+            .field("access_readers", &self.access_readers)
+            .finish()
+    }
+}
+
+/// Pending events exported for offline sync. Unlike a heartbeat request it has no api token.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportData {
+    access_hub: ExportHubData,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportHubData {
+    id: String,
+    #[serde(with = "json_option_naive_date_time")]
+    cloud_last_access_event_at: Option<chrono::NaiveDateTime>,
+    access_events: Vec<AccessEventRequestData>,
 }
 
 #[derive(Debug, Serialize, sqlx::FromRow)]
 #[serde(rename_all = "camelCase")]
 struct AccessEventRequestData {
+    #[serde(skip)]
+    outbox_id: i64,
     #[serde(with = "json_naive_date_time")]
     at: chrono::NaiveDateTime,
     access: String,
     code: String,
     access_user_id: Option<i64>,
     access_point_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_reader_id: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessReaderRequestData {
+    id: i64,
+    name: String,
+    #[serde(with = "json_option_naive_date_time")]
+    last_seen_at: Option<chrono::NaiveDateTime>,
+    access_point_ids: Vec<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -44,6 +101,8 @@ struct AccessHubResponseData {
     id: String,
     #[serde(with = "json_naive_date_time")]
     cloud_last_access_event_at: chrono::NaiveDateTime,
+    #[serde(default, with = "json_option_naive_date_time")]
+    server_time: Option<chrono::NaiveDateTime>,
     access_users: Vec<AccessUserResponseData>,
 }
 
@@ -64,142 +123,232 @@ struct AccessPointResponseData {
     id: i64,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClockSkewAlert {
+    r#type: &'static str,
+    #[serde(with = "json_naive_date_time")]
+    at: chrono::NaiveDateTime,
+    #[serde(with = "json_naive_date_time")]
+    server_time: chrono::NaiveDateTime,
+    skew_seconds: i64,
+}
+
 #[derive(Debug, PartialEq)]
 struct UserWithPointIds {
     user: User,
     point_ids: Vec<i64>,
 }
 
-// https://serde.rs/custom-date-format.html
-// JS Date.toJSON()
-mod json_naive_date_time {
-    use chrono::{NaiveDateTime, Timelike};
-    use serde::{self, Deserialize, Deserializer, Serializer};
+/// Access cloud a heartbeat posts to and how it talks to it.
+#[derive(clap::Args, Clone, Debug)]
+pub struct Cloud {
+    /// Access cloud host
+    #[clap(short = 'a', long, env)]
+    pub access_api_url: Option<String>,
 
-    pub fn serialize<S>(dt: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let s = format!("{}", dt.format("%Y-%m-%dT%H:%M:%S%.3fZ")); // JS Date.toJSON()
-        serializer.serialize_str(&s)
-    }
+    /// Seconds the hub clock may drift from the cloud clock before it is untrusted
+    #[clap(long, env, parse(try_from_str), default_value_t = 30)]
+    pub max_clock_skew: i64,
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        let dt = s
-            .parse::<chrono::DateTime<chrono::Utc>>()
-            .map_err(serde::de::Error::custom)?;
-        Ok(dt.naive_utc().with_nanosecond(0).unwrap())
-    }
+    /// Seconds to wait for the cloud to respond
+    #[clap(
+        long,
+        env = "HEARTBEAT_TIMEOUT",
+        parse(try_from_str),
+        default_value_t = 30
+    )]
+    pub timeout: u64,
 
-    #[test]
-    fn test_json_naive_date_time() {
-        #[derive(Debug, serde::Serialize, Deserialize)]
-        struct S {
-            #[serde(with = "self")]
-            dt: chrono::NaiveDateTime,
-        }
-        let dt = chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(1, 46, 40);
-        let data = S { dt };
-        let json = serde_json::to_string(&data).unwrap();
-        assert_eq!(json, r#"{"dt":"2001-09-08T01:46:40.000Z"}"#);
+    /// Most events uploaded per heartbeat, oldest first. The rest wait for the next one
+    #[clap(long, env = "HEARTBEAT_BATCH_SIZE", parse(try_from_str))]
+    pub batch_size: Option<i64>,
+}
 
-        let data: S = serde_json::from_str(&json).unwrap();
-        assert_eq!(data.dt, dt);
+/// Counts recorded in SyncRun as the heartbeat progresses, so a failed run still
+/// shows how far it got.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncRunCounts {
+    http_status: Option<u16>,
+    events_uploaded: usize,
+    users_created: usize,
+    users_updated: usize,
+    users_deleted: usize,
+    users_skipped: usize,
+    clock_skew_seconds: Option<i64>,
+}
 
-        let result = serde_json::from_str::<S>(r#"{"dt":"2001-09-08T01:46:40.000"}"#);
-        assert!(result.is_err());
-    }
+pub async fn heartbeat(cloud: &Cloud, database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::connect(database_url).await?;
+    let counts = run(cloud, &mut conn).await?;
+    print_counts(&counts);
+    Ok(())
 }
 
-// https://stackoverflow.com/questions/44301748/how-can-i-deserialize-an-optional-field-with-custom-functions-using-serde
-// JS Date.toJSON()
-mod json_option_naive_date_time {
-    use chrono::{NaiveDateTime, Timelike};
-    use serde::{self, Deserialize, Deserializer, Serializer};
+/// Post one heartbeat, recorded as a sync run and in the heartbeat metrics.
+pub async fn run(cloud: &Cloud, conn: &mut SqliteConnection) -> anyhow::Result<SyncRunCounts> {
+    let access_api_url = cloud.access_api_url.as_deref().ok_or_else(|| {
+        anyhow::anyhow!("Missing access api url: pass --access-api-url or set ACCESS_API_URL")
+    })?;
+    let started = std::time::Instant::now();
+    let sync_run_id = start_sync_run(&mut *conn).await?;
+    let mut counts = SyncRunCounts::default();
+    let result = sync(access_api_url, cloud, &mut counts, &mut *conn).await;
+    finish_sync_run(sync_run_id, &counts, &result, &mut *conn).await?;
+    metrics::HEARTBEATS
+        .with_label_values(&[if result.is_ok() { "success" } else { "failure" }])
+        .inc();
+    metrics::HEARTBEAT_DURATION.observe(started.elapsed().as_secs_f64());
+    result.map(|_| counts)
+}
 
-    pub fn serialize<S>(dt: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        if let Some(ref d) = *dt {
-            return crate::heartbeat::json_naive_date_time::serialize(d, serializer);
-        }
-        serializer.serialize_none()
-    }
+/// Write the pending events and the hub id to a file, for air-gapped hubs synced by hand.
+/// The api token stays on the hub and alerts wait for the next heartbeat.
+pub async fn export_events(file: &str, database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::connect(database_url).await?;
+    let hub = load_hub(&mut conn).await?;
+    let (request_data, _) = pending_request(&hub, None, &mut conn).await?;
+    let export_data = ExportData {
+        access_hub: ExportHubData {
+            id: request_data.access_hub.id,
+            cloud_last_access_event_at: request_data.access_hub.cloud_last_access_event_at,
+            access_events: request_data.access_hub.access_events,
+        },
+    };
+    std::fs::write(file, serde_json::to_string_pretty(&export_data)?)?;
+    println!(
+        "Exported {} events to {}",
+        export_data.access_hub.access_events.len(),
+        file
+    );
+    Ok(())
+}
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s: Option<String> = Option::deserialize(deserializer)?;
-        if let Some(s) = s {
-            let dt = s
-                .parse::<chrono::DateTime<chrono::Utc>>()
-                .map_err(serde::de::Error::custom)?;
-            return Ok(Some(dt.naive_utc().with_nanosecond(0).unwrap()));
-        }
-        Ok(None)
+/// Apply a cloud response read from a file through the same sync as a heartbeat. Its
+/// cursor acknowledges the events the cloud received from an earlier export.
+pub async fn import(file: &str, database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::connect(database_url).await?;
+    let sync_run_id = start_sync_run(&mut conn).await?;
+    let mut counts = SyncRunCounts::default();
+    let result = async {
+        let hub = load_hub(&mut conn).await?;
+        let data = parse_response(&std::fs::read_to_string(file)?)?;
+        let skipped_ids = check_response(&hub, &data, &mut conn).await?;
+        // Only events are exported, and the cursor tells which of them the cloud has.
+        apply_response(&hub, data, &[], &skipped_ids, &mut counts, &mut conn).await
     }
+    .await;
+    finish_sync_run(sync_run_id, &counts, &result, &mut conn).await?;
+    result?;
+    print_counts(&counts);
+    Ok(())
+}
 
-    #[test]
-    fn test_json_option_naive_date_time() {
-        #[derive(serde::Serialize, Deserialize)]
-        struct S {
-            #[serde(with = "self")]
-            opt_dt: Option<chrono::NaiveDateTime>,
-        }
-        let dt = chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(1, 46, 40);
-        let opt_dt = Some(dt);
-        let data = S { opt_dt };
-        let json = serde_json::to_string(&data).unwrap();
-        assert_eq!(json, r#"{"opt_dt":"2001-09-08T01:46:40.000Z"}"#);
-
-        let data: S = serde_json::from_str(&json).unwrap();
-        assert_eq!(data.opt_dt, opt_dt);
-
-        let opt_dt = None;
-        let data = S { opt_dt };
-        let json = serde_json::to_string(&data).unwrap();
-        assert_eq!(json, r#"{"opt_dt":null}"#);
+fn print_counts(counts: &SyncRunCounts) {
+    println!(
+        "Uploaded {} events, created {} updated {} deleted {} skipped {} users",
+        counts.events_uploaded,
+        counts.users_created,
+        counts.users_updated,
+        counts.users_deleted,
+        counts.users_skipped
+    );
+}
 
-        let data: S = serde_json::from_str(&json).unwrap();
-        assert_eq!(data.opt_dt, opt_dt);
+async fn start_sync_run(conn: &mut SqliteConnection) -> anyhow::Result<i64> {
+    let id = sqlx::query(r#"insert into SyncRun (started_at) values (CURRENT_TIMESTAMP)"#)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    Ok(id)
+}
 
-        let result = serde_json::from_str::<S>(r#"{"opt_dt":"2001-09-08T01:46:40.000"}"#);
-        assert!(result.is_err());
+async fn finish_sync_run(
+    sync_run_id: i64,
+    counts: &SyncRunCounts,
+    result: &anyhow::Result<()>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let rows_affected = sqlx::query(
+        r#"update SyncRun set ended_at = CURRENT_TIMESTAMP, http_status = ?, events_uploaded = ?,
+        users_created = ?, users_updated = ?, users_deleted = ?, users_skipped = ?,
+        clock_skew_seconds = ?, error = ? where id = ?"#,
+    )
+    .bind(counts.http_status)
+    .bind(counts.events_uploaded as i64)
+    .bind(counts.users_created as i64)
+    .bind(counts.users_updated as i64)
+    .bind(counts.users_deleted as i64)
+    .bind(counts.users_skipped as i64)
+    .bind(counts.clock_skew_seconds)
+    .bind(result.as_ref().err().map(|e| format!("{:#}", e)))
+    .bind(sync_run_id)
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    if rows_affected != 1 {
+        return Err(anyhow::anyhow!(
+            "Update sync run {} affected no rows",
+            sync_run_id
+        ));
     }
+    Ok(())
 }
 
-pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Result<()> {
-    let mut conn = SqliteConnection::connect(database_url).await?;
-    let hub: Hub =
-        sqlx::query_as("select id, api_token, cloud_last_access_event_at from AccessHub")
-            .fetch_one(&mut conn)
-            .await?;
-    println!("{:#?}", hub);
+async fn load_hub(conn: &mut SqliteConnection) -> anyhow::Result<Hub> {
+    let hub = crate::dump::select_hub(&mut *conn).await?;
+    debug!(?hub, "Loaded hub");
 
     if hub.api_token.is_empty() {
-        return Err(anyhow::anyhow!("Missing api token"));
+        return Err(anyhow::anyhow!(
+            "Missing api token: set it with `ahub token --set <token>`"
+        ));
     }
+    Ok(hub)
+}
 
+/// Build the heartbeat request from the outbox. Returns the request and the outbox ids of
+/// the alerts it carries.
+async fn pending_request(
+    hub: &Hub,
+    batch_size: Option<i64>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<(RequestData, Vec<i64>)> {
     let events: Vec<AccessEventRequestData> = match hub.cloud_last_access_event_at {
-        Some(cloud_last_access_event_at) => {
-            // Leave margin to prevent race condition.
+        Some(_) => {
+            // Leave margin to prevent race condition. A batch takes the oldest events since
+            // the cloud cursor acknowledges everything up to the newest one it receives. The
+            // cursor has one second resolution, so a batch is extended to the end of the
+            // second of its last event rather than cut inside it.
             sqlx::query_as(
-                "select at, access, code, access_user_id, access_point_id from AccessEvent 
-                where at > ? and at < DATETIME(CURRENT_TIMESTAMP, '-5 seconds') order by at desc",
+                "with pending as (
+                  select o.id as outbox_id, e.at, e.access, e.code, e.access_user_id, e.access_point_id,
+                    e.access_reader_id
+                  from Outbox o join AccessEvent e on o.record_id = e.id
+                  where o.kind = ? and o.delivered_at is null
+                    and e.at < DATETIME(CURRENT_TIMESTAMP, '-5 seconds'))
+                select * from pending
+                where at <= (select max(at) from (select at from pending order by at asc limit coalesce(?, -1)))
+                order by at desc, outbox_id desc",
             )
-            .bind(cloud_last_access_event_at)
-            .fetch_all(&mut conn)
+            .bind(outbox::EVENT)
+            .bind(batch_size)
+            .fetch_all(&mut *conn)
             .await?
         }
         None => vec![],
     };
-    println!("events {:#?}", events);
+    debug!(events = events.len(), "Pending events");
+
+    let alerts: Vec<(i64, String)> = sqlx::query_as(
+        "select id, payload from Outbox where kind = ? and delivered_at is null and payload is not null order by id asc",
+    )
+    .bind(outbox::ALERT)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let readers = crate::dump::select_readers(&mut *conn).await?;
 
     let request_data = RequestData {
         access_hub: AccessHubRequestData {
@@ -207,23 +356,128 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
             api_token: hub.api_token.clone(),
             cloud_last_access_event_at: hub.cloud_last_access_event_at,
             access_events: events,
+            access_alerts: alerts
+                .iter()
+                .map(|(_, payload)| serde_json::from_str(payload))
+                .collect::<Result<_, _>>()?,
+            access_readers: readers
+                .into_iter()
+                .map(|r| AccessReaderRequestData {
+                    id: r.reader.id,
+                    name: r.reader.name,
+                    last_seen_at: r.reader.last_seen_at,
+                    access_point_ids: r.points.iter().map(|p| p.id).collect(),
+                })
+                .collect(),
         },
     };
-    println!("request_data: {:#?}", request_data);
-    let client = reqwest::Client::new();
-    let res = client
+    Ok((request_data, alerts.iter().map(|(id, _)| *id).collect()))
+}
+
+fn parse_response(body: &str) -> anyhow::Result<ResponseData> {
+    let data: ResponseData = serde_path_to_error::deserialize(
+        &mut serde_json::Deserializer::from_str(body),
+    )
+    .map_err(|e| anyhow::anyhow!("Invalid heartbeat response at {}: {}", e.path(), e.inner()))?;
+    // Ids only, codes are secrets.
+    trace!(
+        cloud_last_access_event_at = %data.access_hub.cloud_last_access_event_at,
+        user_ids = ?data.access_hub.access_users.iter().map(|u| u.id).collect::<Vec<i64>>(),
+        "Heartbeat response"
+    );
+    Ok(data)
+}
+
+async fn sync(
+    access_api_url: &str,
+    cloud: &Cloud,
+    counts: &mut SyncRunCounts,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let hub = load_hub(&mut *conn).await?;
+    let (request_data, alert_ids) = pending_request(&hub, cloud.batch_size, &mut *conn).await?;
+    let event_ids: Vec<i64> = request_data
+        .access_hub
+        .access_events
+        .iter()
+        .map(|e| e.outbox_id)
+        .collect();
+    let outbox_ids: Vec<i64> = event_ids.iter().chain(alert_ids.iter()).copied().collect();
+
+    info!(
+        url = access_api_url,
+        events = request_data.access_hub.access_events.len(),
+        alerts = alert_ids.len(),
+        "Posting heartbeat"
+    );
+    trace!(
+        event_outbox_ids = ?event_ids,
+        ?alert_ids,
+        readers = request_data.access_hub.access_readers.len(),
+        "Heartbeat request"
+    );
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(cloud.timeout))
+        .build()?;
+    let res = match client
         .post(format!("{}/api/accesshub/heartbeat", access_api_url))
         .json(&request_data)
         .send()
-        .await?;
+        .await
+    {
+        Ok(res) => res,
+        Err(err) => {
+            outbox::record_attempt(&outbox_ids, Some(&err.to_string()), &mut *conn).await?;
+            return Err(err.into());
+        }
+    };
 
+    counts.http_status = Some(res.status().as_u16());
     if !res.status().is_success() {
-        return Err(anyhow::anyhow!("Response error: {}", res.text().await?));
+        let message = format!("Response error: {}", res.text().await?);
+        outbox::record_attempt(&outbox_ids, Some(&message), &mut *conn).await?;
+        return Err(anyhow::anyhow!(message));
     }
+    outbox::record_attempt(&outbox_ids, None, &mut *conn).await?;
+
+    let date = res
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_http_date);
+    let received_at = chrono::Utc::now().naive_utc();
+    let data = parse_response(&res.text().await?)?;
+    let server_time = data.access_hub.server_time.or(date);
+
+    let skipped_ids = check_response(&hub, &data, &mut *conn).await?;
+    // Alerts are delivered once the cloud accepted them and answered with a valid response.
+    outbox::acknowledge(&alert_ids, &mut *conn).await?;
+    counts.events_uploaded = event_ids.len();
 
-    let data = res.json::<ResponseData>().await?;
-    println!("response data: {:#?}", data);
+    if let Some(server_time) = server_time {
+        counts.clock_skew_seconds = Some(
+            check_clock_skew(
+                &hub,
+                server_time,
+                received_at,
+                cloud.max_clock_skew,
+                &mut *conn,
+            )
+            .await?,
+        );
+    } else {
+        warn!("Cloud response has no server time or Date header to check clock skew");
+    }
+    apply_response(&hub, data, &event_ids, &skipped_ids, counts, &mut *conn).await
+}
 
+/// Check a cloud response before anything of it is written. Returns the ids of the cloud
+/// users to skip because they clash with local users.
+async fn check_response(
+    hub: &Hub,
+    data: &ResponseData,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<HashSet<i64>> {
     if hub.id != data.access_hub.id {
         return Err(anyhow::anyhow!(
             "Hub id {} does not match cloud hub id {}",
@@ -232,14 +486,62 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
         ));
     }
 
+    let mut local_points = HashMap::<i64, Point>::new();
+    {
+        let mut rows =
+            sqlx::query_as::<_, Point>(r#"select id, position from AccessPoint"#).fetch(&mut *conn);
+        while let Some(u) = rows.try_next().await? {
+            local_points.insert(u.id, u);
+        }
+    }
+
+    let local_users: HashMap<i64, String> =
+        sqlx::query_as::<_, (i64, String)>(r#"select id, code from AccessUser where local"#)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .collect();
+
+    let problems = validate_response(data, &local_points);
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Invalid heartbeat response with {} problem(s):\n{}",
+            problems.len(),
+            problems
+                .iter()
+                .map(|p| format!("  {}", p))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    let mut skipped_ids = HashSet::<i64>::new();
+    for (id, conflict) in local_conflicts(data, &local_users) {
+        warn!("Skipping cloud user {}: {}", id, conflict);
+        skipped_ids.insert(id);
+    }
+    Ok(skipped_ids)
+}
+
+/// Apply a checked cloud response: advance the cursor, acknowledge the event outbox rows it
+/// covers and sync access users. Sent event rows it does not cover are noted as such.
+/// Skipped cloud users are left off the hub like users the cloud no longer has.
+async fn apply_response(
+    hub: &Hub,
+    data: ResponseData,
+    sent_event_ids: &[i64],
+    skipped_ids: &HashSet<i64>,
+    counts: &mut SyncRunCounts,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
     if hub.cloud_last_access_event_at == None
         || hub.cloud_last_access_event_at.unwrap() != data.access_hub.cloud_last_access_event_at
     {
         let rows_affected =
             sqlx::query(r#"update AccessHub set cloud_last_access_event_at = ? where id = ?"#)
                 .bind(data.access_hub.cloud_last_access_event_at)
-                .bind(hub.id)
-                .execute(&mut conn)
+                .bind(&hub.id)
+                .execute(&mut *conn)
                 .await?
                 .rows_affected();
         if rows_affected != 1 {
@@ -249,36 +551,17 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
         }
     }
 
-    let mut local_points = HashMap::<i64, Point>::new();
-    {
-        let mut rows =
-            sqlx::query_as::<_, Point>(r#"select id, position from AccessPoint"#).fetch(&mut conn);
-        while let Some(u) = rows.try_next().await? {
-            local_points.insert(u.id, u);
-        }
-    }
-
-    let invalid_point_ids: HashSet<i64> = data
-        .access_hub
-        .access_users
-        .iter()
-        .flat_map(|u| &u.access_points)
-        .filter(|p| !local_points.contains_key(&p.id))
-        .map(|p| p.id)
-        .collect();
-    if !invalid_point_ids.is_empty() {
-        return Err(anyhow::anyhow!(
-            "Invalid point ids in server response: {:#?}",
-            invalid_point_ids
-        ));
-    }
+    let delivered =
+        outbox::acknowledge_events(data.access_hub.cloud_last_access_event_at, &mut *conn).await?;
+    outbox::record_unacknowledged(sent_event_ids, &mut *conn).await?;
+    info!(delivered, "Acknowledged outbox events");
 
     let mut user2points = HashMap::<i64, Vec<i64>>::new();
     {
         let mut rows = sqlx::query_as::<_, Point2User>(
             r#"select access_user_id, access_point_id from AccessPointToAccessUser"#,
         )
-        .fetch(&mut conn);
+        .fetch(&mut *conn);
         while let Some(u2p) = rows.try_next().await? {
             if let Some(points) = user2points.get_mut(&u2p.access_user_id) {
                 points.push(u2p.access_point_id);
@@ -293,7 +576,7 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
         let mut rows = sqlx::query_as::<_, User>(
             r#"select id, code, activate_code_at, expire_code_at from AccessUser"#,
         )
-        .fetch(&mut conn);
+        .fetch(&mut *conn);
         while let Some(u) = rows.try_next().await? {
             let id = u.id;
             local_users.insert(
@@ -305,22 +588,12 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
             );
         }
     }
-    println!("local_users {:#?}", local_users);
 
     let mut cloud_users = HashMap::<i64, UserWithPointIds>::new();
-    let cloud_users_len = data.access_hub.access_users.len();
+    counts.users_skipped = skipped_ids.len();
     for cloud_user_data in data.access_hub.access_users {
-        if cloud_user_data.code.is_empty() {
-            return Err(anyhow::anyhow!(
-                "Cloud user {} does not have code",
-                cloud_user_data.id
-            ));
-        }
-        if cloud_user_data.access_points.is_empty() {
-            return Err(anyhow::anyhow!(
-                "Cloud user {} does not have any points",
-                cloud_user_data.id
-            ));
+        if skipped_ids.contains(&cloud_user_data.id) {
+            continue;
         }
         cloud_users.insert(
             cloud_user_data.id,
@@ -340,10 +613,6 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
         );
     }
 
-    if cloud_users.len() != cloud_users_len {
-        return Err(anyhow::anyhow!("Duplicate cloud access user id's"));
-    }
-
     let mut common_ids = HashSet::<i64>::new();
     let mut create_users = Vec::<&UserWithPointIds>::new();
     let mut update_users = Vec::<&UserWithPointIds>::new();
@@ -362,9 +631,17 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
         }
     }
 
+    // Users added on the hub with `user add --local` are not the cloud's to delete.
+    let hub_local_ids: HashSet<i64> =
+        sqlx::query_as::<_, (i64,)>(r#"select id from AccessUser where local"#)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|(id,)| id)
+            .collect();
     let delete_ids: HashSet<i64> = local_users
         .keys()
-        .filter(|k| !common_ids.contains(k))
+        .filter(|k| !common_ids.contains(k) && !hub_local_ids.contains(k))
         .copied()
         .collect();
 
@@ -374,13 +651,14 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
         .filter(|x| changed_codes.contains(&*x.user.code))
         .collect();
 
-    println!("cloud_users {:#?}", cloud_users);
-    println!("create_users {:#?}", create_users);
-    println!("update_users {:#?}", update_users);
-    println!("common_ids {:?}", common_ids);
-    println!("delete_ids {:?}", delete_ids);
-    println!("changed_codes {:?}", changed_codes);
-    println!("recycled_code_local_users {:#?}", recycled_code_local_users);
+    let ids = |users: &[&UserWithPointIds]| users.iter().map(|u| u.user.id).collect::<Vec<i64>>();
+    debug!(
+        create_ids = ?ids(&create_users),
+        update_ids = ?ids(&update_users),
+        ?delete_ids,
+        recycled_code_ids = ?ids(&recycled_code_local_users),
+        "Access user changes"
+    );
 
     // Access user codes must be unique: delete, update recyled codes, update, create.
     if delete_ids.is_empty()
@@ -388,8 +666,10 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
         && update_users.is_empty()
         && create_users.is_empty()
     {
-        println!("No changes to access users.")
+        info!("No changes to access users")
     } else {
+        let (users_created, users_updated, users_deleted) =
+            (create_users.len(), update_users.len(), delete_ids.len());
         let mut tx = conn.begin().await?;
         if !delete_ids.is_empty() {
             let query = format!(
@@ -521,7 +801,337 @@ pub async fn heartbeat(access_api_url: &str, database_url: &str) -> anyhow::Resu
             }
         }
         tx.commit().await?;
+        info!(
+            created = users_created,
+            updated = users_updated,
+            deleted = users_deleted,
+            "Synced access users"
+        );
+        counts.users_created = users_created;
+        counts.users_updated = users_updated;
+        counts.users_deleted = users_deleted;
     }
 
     Ok(())
 }
+
+/// Problem found in a heartbeat response, located by its JSON path.
+#[derive(Debug, PartialEq)]
+struct ResponseProblem {
+    path: String,
+    message: String,
+}
+
+impl std::fmt::Display for ResponseProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Collect every problem in the cloud users so the sync can be rejected as a whole
+/// before anything local is changed.
+fn validate_response(
+    data: &ResponseData,
+    local_points: &HashMap<i64, Point>,
+) -> Vec<ResponseProblem> {
+    let mut problems = Vec::<ResponseProblem>::new();
+    let mut problem =
+        |path: String, message: String| problems.push(ResponseProblem { path, message });
+    let mut user_ids = HashMap::<i64, usize>::new();
+    let mut codes = HashMap::<&str, usize>::new();
+    for (i, u) in data.access_hub.access_users.iter().enumerate() {
+        let path = format!("accessHub.accessUsers[{}]", i);
+        if let Some(first) = user_ids.get(&u.id) {
+            problem(
+                format!("{}.id", path),
+                format!(
+                    "duplicate user id {}, first at accessHub.accessUsers[{}]",
+                    u.id, first
+                ),
+            );
+        } else {
+            user_ids.insert(u.id, i);
+        }
+        if u.code.is_empty() {
+            problem(
+                format!("{}.code", path),
+                format!("user {} does not have code", u.id),
+            );
+        } else if let Some(first) = codes.get(u.code.as_str()) {
+            problem(
+                format!("{}.code", path),
+                format!(
+                    "user {} has duplicate code, first at accessHub.accessUsers[{}]",
+                    u.id, first
+                ),
+            );
+        } else {
+            codes.insert(&u.code, i);
+        }
+        if let (Some(activate_code_at), Some(expire_code_at)) =
+            (u.activate_code_at, u.expire_code_at)
+        {
+            if expire_code_at <= activate_code_at {
+                problem(
+                    format!("{}.expireCodeAt", path),
+                    format!("user {} code expires at or before activateCodeAt", u.id),
+                );
+            }
+        }
+
+        if u.access_points.is_empty() {
+            problem(
+                format!("{}.accessPoints", path),
+                format!("user {} does not have any points", u.id),
+            );
+        }
+        let mut point_ids = HashSet::<i64>::new();
+        for (j, p) in u.access_points.iter().enumerate() {
+            if !local_points.contains_key(&p.id) {
+                problem(
+                    format!("{}.accessPoints[{}].id", path, j),
+                    format!("unknown point id {}", p.id),
+                );
+            } else if !point_ids.insert(p.id) {
+                problem(
+                    format!("{}.accessPoints[{}].id", path, j),
+                    format!("duplicate point id {}", p.id),
+                );
+            }
+        }
+    }
+    problems
+}
+
+/// Cloud users whose id or code belongs to a user added on the hub with `--local`. Local
+/// users keep their ids and codes, so these cloud users are skipped rather than the whole
+/// response rejected.
+fn local_conflicts(
+    data: &ResponseData,
+    local_users: &HashMap<i64, String>,
+) -> Vec<(i64, ResponseProblem)> {
+    let local_codes: HashMap<&str, i64> = local_users
+        .iter()
+        .map(|(id, code)| (code.as_str(), *id))
+        .collect();
+    let mut conflicts = Vec::<(i64, ResponseProblem)>::new();
+    for (i, u) in data.access_hub.access_users.iter().enumerate() {
+        let path = format!("accessHub.accessUsers[{}]", i);
+        if local_users.contains_key(&u.id) {
+            conflicts.push((
+                u.id,
+                ResponseProblem {
+                    path: format!("{}.id", path),
+                    message: format!("user id {} belongs to a local user", u.id),
+                },
+            ));
+        } else if let Some(local_id) = local_codes.get(u.code.as_str()) {
+            conflicts.push((
+                u.id,
+                ResponseProblem {
+                    path: format!("{}.code", path),
+                    message: format!("user {} code belongs to local user {}", u.id, local_id),
+                },
+            ));
+        }
+    }
+    conflicts
+}
+
+/// Parse an HTTP Date header (IMF-fixdate) into UTC.
+fn parse_http_date(s: &str) -> Option<chrono::NaiveDateTime> {
+    chrono::DateTime::parse_from_rfc2822(s)
+        .ok()
+        .map(|dt| dt.naive_utc())
+}
+
+/// Record the skew between the cloud and hub clocks. Access decisions compare codes against
+/// the hub clock, so a skew past max_clock_skew marks the clock untrusted and queues an alert
+/// the first time it is exceeded.
+async fn check_clock_skew(
+    hub: &Hub,
+    server_time: chrono::NaiveDateTime,
+    local_time: chrono::NaiveDateTime,
+    max_clock_skew: i64,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<i64> {
+    let skew_seconds = (server_time - local_time).num_seconds();
+    let exceeded = skew_seconds.abs() > max_clock_skew;
+    debug!(skew_seconds, "Clock skew");
+    if exceeded {
+        warn!(
+            "Hub clock is {}s {} cloud clock, more than the {}s allowed",
+            skew_seconds.abs(),
+            if skew_seconds > 0 {
+                "behind"
+            } else {
+                "ahead of"
+            },
+            max_clock_skew
+        );
+        if !hub.clock_skew_exceeded {
+            let alert = ClockSkewAlert {
+                r#type: "clockSkew",
+                at: local_time,
+                server_time,
+                skew_seconds,
+            };
+            outbox::enqueue(outbox::ALERT, &serde_json::to_string(&alert)?, &mut *conn).await?;
+        }
+    }
+
+    let rows_affected = sqlx::query(
+        r#"update AccessHub set clock_skew_seconds = ?, clock_skew_exceeded = ? where id = ?"#,
+    )
+    .bind(skew_seconds)
+    .bind(exceeded)
+    .bind(&hub.id)
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    if rows_affected != 1 {
+        return Err(anyhow::anyhow!("Update clock skew affected no rows"));
+    }
+    Ok(skew_seconds)
+}
+
+#[test]
+fn test_parse_http_date() {
+    let dt = parse_http_date("Sat, 08 Sep 2001 01:46:40 GMT").unwrap();
+    assert_eq!(
+        dt,
+        chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(1, 46, 40)
+    );
+    assert!(parse_http_date("2001-09-08T01:46:40.000Z").is_none());
+}
+
+#[test]
+fn test_validate_response() {
+    let data: ResponseData = serde_json::from_str(
+        r#"{"accessHub":{"id":"hub","cloudLastAccessEventAt":"2001-09-08T01:46:40.000Z","accessUsers":[
+            {"id":1,"code":"111","activateCodeAt":null,"expireCodeAt":null,"accessPoints":[{"id":1}]},
+            {"id":1,"code":"","activateCodeAt":null,"expireCodeAt":null,"accessPoints":[]},
+            {"id":2,"code":"111","activateCodeAt":"2001-09-08T01:46:40.000Z","expireCodeAt":"2001-09-08T01:46:40.000Z","accessPoints":[{"id":9},{"id":1},{"id":1}]},
+            {"id":3,"code":"999","activateCodeAt":null,"expireCodeAt":null,"accessPoints":[{"id":1}]},
+            {"id":-1,"code":"888","activateCodeAt":null,"expireCodeAt":null,"accessPoints":[{"id":1}]}
+        ]}}"#,
+    )
+    .unwrap();
+    let local_points = HashMap::from([(1, Point { id: 1, position: 1 })]);
+    let local_users = HashMap::from([(-1, "999".to_string())]);
+    let problems: Vec<String> = validate_response(&data, &local_points)
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    assert_eq!(
+        problems,
+        vec![
+            "accessHub.accessUsers[1].id: duplicate user id 1, first at accessHub.accessUsers[0]",
+            "accessHub.accessUsers[1].code: user 1 does not have code",
+            "accessHub.accessUsers[1].accessPoints: user 1 does not have any points",
+            "accessHub.accessUsers[2].code: user 2 has duplicate code, first at accessHub.accessUsers[0]",
+            "accessHub.accessUsers[2].expireCodeAt: user 2 code expires at or before activateCodeAt",
+            "accessHub.accessUsers[2].accessPoints[0].id: unknown point id 9",
+            "accessHub.accessUsers[2].accessPoints[2].id: duplicate point id 1",
+        ]
+    );
+    let conflicts: Vec<(i64, String)> = local_conflicts(&data, &local_users)
+        .iter()
+        .map(|(id, p)| (*id, p.to_string()))
+        .collect();
+    assert_eq!(
+        conflicts,
+        vec![
+            (
+                3,
+                "accessHub.accessUsers[3].code: user 3 code belongs to local user -1".into()
+            ),
+            (
+                -1,
+                "accessHub.accessUsers[4].id: user id -1 belongs to a local user".into()
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_pending_request_batch() {
+    let mut conn = crate::db::test_conn().await;
+    sqlx::query(
+        r#"update AccessHub set cloud_last_access_event_at = '2001-09-08 01:46:00';
+        insert into AccessEvent (at, access, code, access_point_id) values
+          ('2001-09-08 01:46:40', 'grant', '1', 1),
+          ('2001-09-08 01:46:40', 'grant', '2', 1),
+          ('2001-09-08 01:46:40', 'grant', '3', 1),
+          ('2001-09-08 01:46:41', 'grant', '4', 1);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let hub = load_hub(&mut conn).await.unwrap();
+
+    // The batch does not end inside the second the cloud cursor will acknowledge.
+    let (request_data, _) = pending_request(&hub, Some(2), &mut conn).await.unwrap();
+    let codes: Vec<&str> = request_data
+        .access_hub
+        .access_events
+        .iter()
+        .map(|e| e.code.as_str())
+        .collect();
+    assert_eq!(codes, vec!["3", "2", "1"]);
+
+    let sent_ids: Vec<i64> = request_data
+        .access_hub
+        .access_events
+        .iter()
+        .map(|e| e.outbox_id)
+        .collect();
+    let cursor = chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(1, 46, 40);
+    let delivered = outbox::acknowledge_events(cursor, &mut conn).await.unwrap();
+    assert_eq!(delivered, 3);
+    outbox::record_unacknowledged(&sent_ids, &mut conn)
+        .await
+        .unwrap();
+    let pending: Vec<(i64, Option<String>)> =
+        sqlx::query_as(r#"select id, last_error from Outbox where delivered_at is null"#)
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert!(!sent_ids.contains(&pending[0].0));
+    assert_eq!(pending[0].1, None);
+    let (request_data, _) = pending_request(&hub, Some(2), &mut conn).await.unwrap();
+    assert_eq!(request_data.access_hub.access_events.len(), 1);
+    assert_eq!(request_data.access_hub.access_events[0].code, "4");
+}
+
+#[tokio::test]
+async fn test_apply_response_skips_local_conflicts() {
+    let mut conn = crate::db::test_conn().await;
+    sqlx::query(r#"insert into AccessUser (id, code, local) values (-1, '999', true)"#)
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    let hub = load_hub(&mut conn).await.unwrap();
+    let data = parse_response(&format!(
+        r#"{{"accessHub":{{"id":"{}","cloudLastAccessEventAt":"2001-09-08T01:46:40.000Z","accessUsers":[
+            {{"id":1,"code":"111","activateCodeAt":null,"expireCodeAt":null,"accessPoints":[{{"id":1}}]}},
+            {{"id":3,"code":"999","activateCodeAt":null,"expireCodeAt":null,"accessPoints":[{{"id":1}}]}}
+        ]}}}}"#,
+        hub.id
+    ))
+    .unwrap();
+    let skipped_ids = check_response(&hub, &data, &mut conn).await.unwrap();
+    assert_eq!(skipped_ids, HashSet::from([3]));
+    let mut counts = SyncRunCounts::default();
+    apply_response(&hub, data, &[], &skipped_ids, &mut counts, &mut conn)
+        .await
+        .unwrap();
+    assert_eq!((counts.users_created, counts.users_skipped), (1, 1));
+    let users: Vec<(i64, String)> =
+        sqlx::query_as(r#"select id, code from AccessUser order by id"#)
+            .fetch_all(&mut conn)
+            .await
+            .unwrap();
+    assert_eq!(users, vec![(-1, "999".into()), (1, "111".into())]);
+}
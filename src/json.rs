@@ -0,0 +1,113 @@
+//! Serde helpers shared by the JSON the hub reads and writes.
+
+use serde::Serializer;
+
+/// Serialize a secret as the redacted marker, so output meant for people or files does
+/// not carry it.
+pub fn redacted<T, S: Serializer>(_: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(crate::logging::REDACTED)
+}
+
+// https://serde.rs/custom-date-format.html
+// JS Date.toJSON()
+pub mod json_naive_date_time {
+    use chrono::{NaiveDateTime, Timelike};
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(dt: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let s = format!("{}", dt.format("%Y-%m-%dT%H:%M:%S%.3fZ")); // JS Date.toJSON()
+        serializer.serialize_str(&s)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<NaiveDateTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let dt = s
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .map_err(serde::de::Error::custom)?;
+        Ok(dt.naive_utc().with_nanosecond(0).unwrap())
+    }
+
+    #[test]
+    fn test_json_naive_date_time() {
+        #[derive(Debug, serde::Serialize, Deserialize)]
+        struct S {
+            #[serde(with = "self")]
+            dt: chrono::NaiveDateTime,
+        }
+        let dt = chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(1, 46, 40);
+        let data = S { dt };
+        let json = serde_json::to_string(&data).unwrap();
+        assert_eq!(json, r#"{"dt":"2001-09-08T01:46:40.000Z"}"#);
+
+        let data: S = serde_json::from_str(&json).unwrap();
+        assert_eq!(data.dt, dt);
+
+        let result = serde_json::from_str::<S>(r#"{"dt":"2001-09-08T01:46:40.000"}"#);
+        assert!(result.is_err());
+    }
+}
+
+// https://stackoverflow.com/questions/44301748/how-can-i-deserialize-an-optional-field-with-custom-functions-using-serde
+// JS Date.toJSON()
+pub mod json_option_naive_date_time {
+    use chrono::{NaiveDateTime, Timelike};
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(dt: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if let Some(ref d) = *dt {
+            return super::json_naive_date_time::serialize(d, serializer);
+        }
+        serializer.serialize_none()
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s: Option<String> = Option::deserialize(deserializer)?;
+        if let Some(s) = s {
+            let dt = s
+                .parse::<chrono::DateTime<chrono::Utc>>()
+                .map_err(serde::de::Error::custom)?;
+            return Ok(Some(dt.naive_utc().with_nanosecond(0).unwrap()));
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn test_json_option_naive_date_time() {
+        #[derive(serde::Serialize, Deserialize)]
+        struct S {
+            #[serde(with = "self")]
+            opt_dt: Option<chrono::NaiveDateTime>,
+        }
+        let dt = chrono::NaiveDate::from_ymd(2001, 9, 8).and_hms(1, 46, 40);
+        let opt_dt = Some(dt);
+        let data = S { opt_dt };
+        let json = serde_json::to_string(&data).unwrap();
+        assert_eq!(json, r#"{"opt_dt":"2001-09-08T01:46:40.000Z"}"#);
+
+        let data: S = serde_json::from_str(&json).unwrap();
+        assert_eq!(data.opt_dt, opt_dt);
+
+        let opt_dt = None;
+        let data = S { opt_dt };
+        let json = serde_json::to_string(&data).unwrap();
+        assert_eq!(json, r#"{"opt_dt":null}"#);
+
+        let data: S = serde_json::from_str(&json).unwrap();
+        assert_eq!(data.opt_dt, opt_dt);
+
+        let result = serde_json::from_str::<S>(r#"{"opt_dt":"2001-09-08T01:46:40.000"}"#);
+        assert!(result.is_err());
+    }
+}
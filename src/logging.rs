@@ -0,0 +1,128 @@
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+/// Shown in place of secrets such as the hub api token when a record is logged.
+pub const REDACTED: &str = "<redacted>";
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogTarget {
+    Stderr,
+    File,
+    Journald,
+}
+
+/// Log records go to stderr, a file or journald and never mix with command results on
+/// stdout.
+#[derive(clap::Args, Debug)]
+pub struct Logging {
+    /// More log detail: -v for debug, -vv for trace
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Less log detail: -q for errors only, -qq for none
+    #[clap(
+        short,
+        long,
+        global = true,
+        parse(from_occurrences),
+        conflicts_with = "verbose"
+    )]
+    quiet: u8,
+
+    /// Where log records go
+    #[clap(
+        long,
+        global = true,
+        arg_enum,
+        env = "AHUB_LOG",
+        default_value = "stderr"
+    )]
+    log: LogTarget,
+
+    /// File log records are appended to with --log file
+    #[clap(long, global = true, env = "AHUB_LOG_FILE", default_value = "ahub.log")]
+    log_file: String,
+
+    /// Write log records as JSON lines
+    #[clap(long, global = true, env = "AHUB_LOG_JSON")]
+    pub log_json: bool,
+}
+
+/// Install the global subscriber. RUST_LOG, when set, replaces the level from -v and -q.
+pub fn init(logging: &Logging) -> anyhow::Result<()> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives)?,
+        Err(_) => EnvFilter::try_new(directives(logging.verbose, logging.quiet))?,
+    };
+    let writer = match logging.log {
+        LogTarget::Stderr => Some(BoxMakeWriter::new(std::io::stderr)),
+        LogTarget::File => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&logging.log_file)
+                .map_err(|e| anyhow::anyhow!("Open log file {}: {}", logging.log_file, e))?;
+            Some(BoxMakeWriter::new(Mutex::new(file)))
+        }
+        LogTarget::Journald => None,
+    };
+    let (text, json) = match writer {
+        Some(writer) if logging.log_json => (
+            None,
+            Some(tracing_subscriber::fmt::layer().json().with_writer(writer)),
+        ),
+        Some(writer) => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(writer),
+            ),
+            None,
+        ),
+        None => (None, None),
+    };
+    let journald = match logging.log {
+        LogTarget::Journald => Some(tracing_journald::layer()?),
+        _ => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with(journald)
+        .try_init()?;
+    Ok(())
+}
+
+/// Filter for -v and -q. Dependencies such as sqlx, which logs every statement, stay at
+/// warn unless RUST_LOG says otherwise.
+fn directives(verbose: u8, quiet: u8) -> &'static str {
+    match (verbose, quiet) {
+        (0, 0) => "warn,ahub=info",
+        (1, _) => "warn,ahub=debug",
+        (_, 0) => "warn,ahub=trace",
+        (_, 1) => "error",
+        _ => "off",
+    }
+}
+
+#[test]
+fn test_logging() {
+    assert_eq!(directives(0, 0), "warn,ahub=info");
+    assert_eq!(directives(2, 0), "warn,ahub=trace");
+    assert_eq!(directives(0, 1), "error");
+    assert_eq!(directives(0, 2), "off");
+
+    let hub = crate::domain::Hub {
+        id: "hub".into(),
+        api_token: "secret".into(),
+        cloud_last_access_event_at: None,
+        clock_skew_seconds: None,
+        clock_skew_exceeded: false,
+    };
+    let debug = format!("{:?}", hub);
+    assert!(!debug.contains("secret"));
+    assert!(debug.contains(REDACTED));
+}
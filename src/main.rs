@@ -1,21 +1,85 @@
-use clap::{Parser, Subcommand};
-use sqlx::{Connection, SqliteConnection};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use format::Format;
 
 mod access;
+mod admin;
+mod api;
+mod backup;
+mod config;
+mod db;
+mod doctor;
 mod domain;
 mod dump;
+mod events;
+mod format;
 mod heartbeat;
+mod json;
+mod logging;
+mod metrics;
+mod migrate;
 mod mock;
+mod outbox;
+mod prune;
+mod reader;
+mod report;
 mod sandbox;
+mod serve;
+mod snapshot;
+mod systemd;
 mod token;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
+    /// Output format of dump commands
+    #[clap(long, global = true, arg_enum, default_value = "debug")]
+    format: Format,
+
+    /// Config file with named profiles
+    #[clap(long, global = true, env = "AHUB_CONFIG", default_value = config::DEFAULT_FILE)]
+    config: String,
+
+    /// Profile of the config file supplying settings not given as options or env vars
+    #[clap(long, global = true, env = "AHUB_PROFILE")]
+    profile: Option<String>,
+
+    #[clap(flatten)]
+    logging: logging::Logging,
+
     #[clap(subcommand)]
     command: Command,
 }
 
+impl Cli {
+    /// Parse the command line with the profile and `.env` settings as defaults.
+    fn parse_with(defaults: &config::Defaults) -> Result<Self, clap::Error> {
+        let matches = defaults.command(Cli::command()).try_get_matches()?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        cli.logging.log_json = defaults.flag(cli.logging.log_json, "AHUB_LOG_JSON");
+        match &mut cli.command {
+            Command::Heartbeat { retention, .. } | Command::Prune { retention, .. } => {
+                retention.vacuum = defaults.flag(retention.vacuum, "RETENTION_VACUUM");
+            }
+            Command::Serve {
+                service, retention, ..
+            } => {
+                retention.vacuum = defaults.flag(retention.vacuum, "RETENTION_VACUUM");
+                service.deny_untrusted_clock =
+                    defaults.flag(service.deny_untrusted_clock, "DENY_UNTRUSTED_CLOCK");
+            }
+            Command::Access {
+                deny_untrusted_clock,
+                ..
+            } => {
+                *deny_untrusted_clock =
+                    defaults.flag(*deny_untrusted_clock, "DENY_UNTRUSTED_CLOCK");
+            }
+            _ => {}
+        }
+        Ok(cli)
+    }
+}
+
 #[derive(Subcommand)]
 enum Command {
     /// Dump database
@@ -27,6 +91,55 @@ enum Command {
         #[clap(subcommand)]
         command: DumpCommand,
     },
+    /// Check database consistency
+    Doctor {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        /// Repair findings that are safe to fix automatically
+        #[clap(long)]
+        fix: bool,
+    },
+    /// Add, update and remove access users while the cloud is unreachable
+    User {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        /// Who makes the change, recorded with it. Defaults to the login user
+        #[clap(long, env = "AHUB_ACTOR")]
+        actor: Option<String>,
+
+        #[clap(subcommand)]
+        command: UserCommand,
+    },
+    /// Add and remove access points
+    Point {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        /// Who makes the change, recorded with it. Defaults to the login user
+        #[clap(long, env = "AHUB_ACTOR")]
+        actor: Option<String>,
+
+        #[clap(subcommand)]
+        command: PointCommand,
+    },
+    /// Add, update and remove networked readers and the points they may ask for
+    Reader {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        /// Who makes the change, recorded with it. Defaults to the login user
+        #[clap(long, env = "AHUB_ACTOR")]
+        actor: Option<String>,
+
+        #[clap(subcommand)]
+        command: ReaderCommand,
+    },
     /// Mock access
     Mock {
         /// Location of the DB, by default will be read from the DATABASE_URL env var
@@ -38,15 +151,98 @@ enum Command {
     },
     /// Post heartbeat to access cloud
     Heartbeat {
-        /// Access cloud host
-        #[clap(short = 'a', long, env)]
-        access_api_url: String,
+        #[clap(flatten)]
+        cloud: heartbeat::Cloud,
+
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        /// Prune uploaded events after a successful heartbeat
+        #[clap(flatten)]
+        retention: prune::Retention,
+
+        #[clap(subcommand)]
+        command: Option<HeartbeatCommand>,
+    },
+    /// Prune access events the cloud has acknowledged
+    Prune {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        #[clap(flatten)]
+        retention: prune::Retention,
+    },
+    /// Run the hub as a service with /metrics, /api and /access endpoints and optional heartbeats
+    Serve {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        #[clap(flatten)]
+        service: serve::Service,
+
+        #[clap(flatten)]
+        cloud: heartbeat::Cloud,
+
+        /// Prune uploaded events after each successful heartbeat
+        #[clap(flatten)]
+        retention: prune::Retention,
+    },
+    /// Report access statistics
+    Report {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        #[clap(flatten)]
+        filter: events::EventFilter,
+
+        #[clap(subcommand)]
+        command: ReportCommand,
+    },
+    /// Export or import a full database snapshot as JSON
+    Snapshot {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
 
+        #[clap(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Take a consistent online backup of the database and rotate old backups
+    Backup {
         /// Location of the DB, by default will be read from the DATABASE_URL env var
         #[clap(long, short = 'D', env)]
         database_url: String,
+
+        /// Number of backups to keep
+        #[clap(short, long, parse(try_from_str), default_value_t = 7)]
+        keep: usize,
+
+        /// Directory for backup files
+        dir: String,
+    },
+    /// Replace the database with a backup file
+    Restore {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        /// Backup file to restore
+        file: String,
     },
-    /// API token
+    /// Apply embedded schema migrations, creating the database if missing
+    Migrate {
+        /// Location of the DB, by default will be read from the DATABASE_URL env var
+        #[clap(long, short = 'D', env)]
+        database_url: String,
+
+        #[clap(subcommand)]
+        command: Option<MigrateCommand>,
+    },
+    /// API token. A new database is provisioned with the hub id and token from the cloud
     Token {
         /// Location of the DB, by default will be read from the DATABASE_URL env var
         #[clap(long, short = 'D', env)]
@@ -55,6 +251,10 @@ enum Command {
         /// Set
         #[clap(short, long, default_value_t = String::from(""))]
         set: String,
+
+        /// Hub id the cloud assigned, which creates the hub of a new database with the token
+        #[clap(long)]
+        hub_id: Option<String>,
     },
     /// Access with code for point at position. Position is 1-based. Returns "GRANT" | "DENY"
     Access {
@@ -66,6 +266,10 @@ enum Command {
         #[clap(short, long, parse(try_from_str))]
         position: i64,
 
+        /// Deny codes with activate or expire times while heartbeat reports excessive clock skew
+        #[clap(long, env)]
+        deny_untrusted_clock: bool,
+
         /// Location of the DB, by default will be read from the DATABASE_URL env var
         #[clap(long, short = 'D', env)]
         database_url: String,
@@ -96,22 +300,296 @@ enum DumpCommand {
         #[clap(short, long, parse(try_from_str), default_value_t = 0)]
         skip: i32,
     },
-    /// Dump events
+    /// Dump events, newest first
     Events {
+        #[clap(flatten)]
+        filter: events::EventFilter,
+
         /// Number of events to take
         #[clap(short, long, parse(try_from_str), default_value_t = 10)]
         take: i32,
 
-        /// Number of events to skip
-        #[clap(short, long, parse(try_from_str), default_value_t = 0)]
-        skip: i32,
+        /// Only events with id less than this, the last id of the previous page
+        #[clap(short, long, parse(try_from_str))]
+        before: Option<i64>,
+
+        /// Keep printing new events as they are inserted, oldest first
+        #[clap(short, long, conflicts_with = "before")]
+        follow: bool,
+
+        /// Milliseconds between polls for new events when following
+        #[clap(long, parse(try_from_str), default_value_t = 500)]
+        interval: u64,
     },
     /// Dump active codes
     Codes {},
+    /// Dump readers and their point positions
+    Readers {},
+    /// Dump outbox entries waiting for delivery to access cloud
+    Outbox {
+        /// Number of entries to take
+        #[clap(short, long, parse(try_from_str), default_value_t = 50)]
+        take: i32,
+
+        /// Number of entries to skip
+        #[clap(short, long, parse(try_from_str), default_value_t = 0)]
+        skip: i32,
+
+        /// Include delivered entries
+        #[clap(short, long)]
+        all: bool,
+    },
+    /// Dump heartbeat sync runs, most recent first
+    Syncs {
+        /// Number of sync runs to take
+        #[clap(short, long, parse(try_from_str), default_value_t = 10)]
+        take: i32,
+
+        /// Number of sync runs to skip
+        #[clap(short, long, parse(try_from_str), default_value_t = 0)]
+        skip: i32,
+
+        /// Only failed sync runs
+        #[clap(short, long)]
+        failed: bool,
+    },
+    /// Dump user and point changes made on the hub, most recent first
+    Changes {
+        /// Number of changes to take
+        #[clap(short, long, parse(try_from_str), default_value_t = 50)]
+        take: i32,
+
+        /// Number of changes to skip
+        #[clap(short, long, parse(try_from_str), default_value_t = 0)]
+        skip: i32,
+    },
     /// Dump sqlite version
     SqliteVersion {},
 }
 
+#[derive(Subcommand, Debug)]
+enum HeartbeatCommand {
+    /// Export pending events and the hub id to a file for offline sync
+    ExportEvents {
+        /// Request file to write
+        file: String,
+    },
+    /// Import a cloud heartbeat response file for offline sync
+    Import {
+        /// Response file to read
+        file: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MigrateCommand {
+    /// Show applied and pending migrations
+    Status {},
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Write hub, points, users, readers, their assignments and events to a versioned JSON file
+    Export {
+        /// Snapshot file to write
+        file: String,
+    },
+    /// Restore a snapshot file into a database without users, readers or events
+    Import {
+        /// Snapshot file to read
+        file: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ReportCommand {
+    /// Grant and deny counts per point, busiest first
+    Points {
+        /// Number of points to take
+        #[clap(short, long, parse(try_from_str), default_value_t = 50)]
+        top: i32,
+    },
+    /// Grant and deny counts per user, busiest first
+    Users {
+        /// Number of users to take
+        #[clap(short, long, parse(try_from_str), default_value_t = 50)]
+        top: i32,
+    },
+    /// Grant and deny counts per hour or day
+    Timeline {
+        /// Bucket size
+        #[clap(short, long, arg_enum, default_value = "hour")]
+        bucket: report::Bucket,
+    },
+    /// Most denied codes
+    DeniedCodes {
+        /// Number of codes to take
+        #[clap(short, long, parse(try_from_str), default_value_t = 10)]
+        top: i32,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCommand {
+    /// Add user
+    Add {
+        /// User id, negative for local users. By default one below the smallest local id or
+        /// one above the largest cloud id
+        #[clap(long, parse(try_from_str), allow_hyphen_values = true)]
+        id: Option<i64>,
+
+        #[clap(flatten)]
+        fields: admin::UserFields,
+
+        /// Point positions (1-based) the code opens
+        #[clap(short, long, parse(try_from_str), multiple_occurrences = true)]
+        position: Vec<i64>,
+
+        /// Keep the user when the next heartbeat does not know it
+        #[clap(long)]
+        local: bool,
+    },
+    /// Update user code and activation times
+    Update {
+        /// User id
+        #[clap(long, parse(try_from_str), allow_hyphen_values = true)]
+        id: i64,
+
+        #[clap(flatten)]
+        fields: admin::UserFields,
+
+        /// Remove activation time
+        #[clap(long, conflicts_with = "activate-code-at")]
+        clear_activate_code_at: bool,
+
+        /// Remove expiry time
+        #[clap(long, conflicts_with = "expire-code-at")]
+        clear_expire_code_at: bool,
+
+        /// Keep the user when the next heartbeat does not know it (true|false)
+        #[clap(long, parse(try_from_str))]
+        local: Option<bool>,
+    },
+    /// Create or update users from a CSV file with columns id, code, activateCodeAt,
+    /// expireCodeAt and positions (space separated). Without positions users keep their points
+    Import {
+        /// CSV file to read
+        file: String,
+
+        /// Keep the users when the next heartbeat does not know them
+        #[clap(long)]
+        local: bool,
+    },
+    /// Remove user and its assignments
+    Remove {
+        /// User id
+        #[clap(long, parse(try_from_str), allow_hyphen_values = true)]
+        id: i64,
+    },
+    /// Assign user to point at position
+    Assign {
+        /// User id
+        #[clap(long, parse(try_from_str), allow_hyphen_values = true)]
+        id: i64,
+
+        /// Point position (1-based)
+        #[clap(short, long, parse(try_from_str))]
+        position: i64,
+    },
+    /// Unassign user from point at position
+    Unassign {
+        /// User id
+        #[clap(long, parse(try_from_str), allow_hyphen_values = true)]
+        id: i64,
+
+        /// Point position (1-based)
+        #[clap(short, long, parse(try_from_str))]
+        position: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PointCommand {
+    /// Add point at position
+    Add {
+        /// Point id, by default one more than the largest
+        #[clap(long, parse(try_from_str))]
+        id: Option<i64>,
+
+        /// Point position (1-based)
+        #[clap(short, long, parse(try_from_str))]
+        position: i64,
+    },
+    /// Remove point at position and its assignments
+    Remove {
+        /// Point position (1-based)
+        #[clap(short, long, parse(try_from_str))]
+        position: i64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ReaderCommand {
+    /// Add reader and print its secret
+    Add {
+        /// Reader id, by default one more than any reader ever had
+        #[clap(long, parse(try_from_str))]
+        id: Option<i64>,
+
+        /// Name
+        #[clap(short, long)]
+        name: String,
+
+        /// Secret the reader authenticates with, by default a random one
+        #[clap(long)]
+        secret: Option<String>,
+
+        /// Point positions (1-based) the reader may ask for
+        #[clap(short, long, parse(try_from_str), multiple_occurrences = true)]
+        position: Vec<i64>,
+    },
+    /// Rename reader or replace its secret
+    Update {
+        /// Reader id
+        #[clap(long, parse(try_from_str))]
+        id: i64,
+
+        /// Name
+        #[clap(short, long)]
+        name: Option<String>,
+
+        /// Replace the secret with a random one and print it
+        #[clap(long)]
+        rotate_secret: bool,
+    },
+    /// Remove reader and its assignments
+    Remove {
+        /// Reader id
+        #[clap(long, parse(try_from_str))]
+        id: i64,
+    },
+    /// Let reader ask for point at position
+    Assign {
+        /// Reader id
+        #[clap(long, parse(try_from_str))]
+        id: i64,
+
+        /// Point position (1-based)
+        #[clap(short, long, parse(try_from_str))]
+        position: i64,
+    },
+    /// Stop reader asking for point at position
+    Unassign {
+        /// Reader id
+        #[clap(long, parse(try_from_str))]
+        id: i64,
+
+        /// Point position (1-based)
+        #[clap(short, long, parse(try_from_str))]
+        position: i64,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum MockCommand {
     /// Mock grant
@@ -136,37 +614,195 @@ enum MockCommand {
     },
     /// Swap codes of first two access users. Way to test recycled codes.
     Swap {},
+    /// Add the development hub and points 1-4 to a new database
+    Seed {},
 }
 
 // #[async_std::main]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    dotenv::dotenv().ok();
-    let args = Cli::parse();
+    let defaults = config::load()?;
+    let args = Cli::parse_with(&defaults).unwrap_or_else(|e| e.exit());
+    logging::init(&args.logging)?;
     match args.command {
         Command::Dump {
             database_url,
             command,
         } => {
-            let mut conn = SqliteConnection::connect(&database_url).await?;
+            let mut conn = db::connect(&database_url).await?;
             match command {
                 DumpCommand::Hub {} => {
-                    dump::dump_hub(&mut conn).await?;
+                    dump::dump_hub(args.format, &mut conn).await?;
                 }
                 DumpCommand::Points { take, skip } => {
-                    dump::dump_points(take, skip, &mut conn).await?;
+                    dump::dump_points(take, skip, args.format, &mut conn).await?;
                 }
                 DumpCommand::Users { take, skip } => {
-                    dump::dump_users(take, skip, &mut conn).await?;
+                    dump::dump_users(take, skip, args.format, &mut conn).await?;
                 }
-                DumpCommand::Events { take, skip } => {
-                    dump::dump_events(take, skip, &mut conn).await?;
+                DumpCommand::Events {
+                    filter,
+                    take,
+                    before,
+                    follow,
+                    interval,
+                } => {
+                    if follow {
+                        let interval = std::time::Duration::from_millis(interval);
+                        dump::follow_events(&filter, take, interval, args.format, &mut conn)
+                            .await?;
+                    } else {
+                        dump::dump_events(&filter, take, before, args.format, &mut conn).await?;
+                    }
                 }
                 DumpCommand::Codes {} => {
-                    dump::dump_codes(&mut conn).await?;
+                    dump::dump_codes(args.format, &mut conn).await?;
+                }
+                DumpCommand::Readers {} => {
+                    dump::dump_readers(args.format, &mut conn).await?;
+                }
+                DumpCommand::Outbox { take, skip, all } => {
+                    dump::dump_outbox(take, skip, all, args.format, &mut conn).await?;
+                }
+                DumpCommand::Syncs { take, skip, failed } => {
+                    dump::dump_syncs(take, skip, failed, args.format, &mut conn).await?;
+                }
+                DumpCommand::Changes { take, skip } => {
+                    dump::dump_changes(take, skip, args.format, &mut conn).await?;
                 }
                 DumpCommand::SqliteVersion {} => {
-                    dump::dump_sqlite_version(&mut conn).await?;
+                    dump::dump_sqlite_version(args.format, &mut conn).await?;
+                }
+            }
+        }
+        Command::Doctor { database_url, fix } => {
+            doctor::doctor(fix, args.format, &database_url).await?
+        }
+        Command::User {
+            database_url,
+            actor,
+            command,
+        } => {
+            let mut conn = db::connect(&database_url).await?;
+            let actor = admin::actor(actor);
+            match command {
+                UserCommand::Add {
+                    id,
+                    fields,
+                    position,
+                    local,
+                } => {
+                    let id =
+                        admin::add_user(id, &fields, &position, local, &actor, &mut conn).await?;
+                    println!("Added user {}", id);
+                }
+                UserCommand::Update {
+                    id,
+                    fields,
+                    clear_activate_code_at,
+                    clear_expire_code_at,
+                    local,
+                } => {
+                    admin::update_user(
+                        id,
+                        &fields,
+                        clear_activate_code_at,
+                        clear_expire_code_at,
+                        local,
+                        &actor,
+                        &mut conn,
+                    )
+                    .await?;
+                    println!("Updated user {}", id);
+                }
+                UserCommand::Import { file, local } => {
+                    admin::import_users(&file, local, &actor, &mut conn).await?
+                }
+                UserCommand::Remove { id } => {
+                    admin::remove_user(id, &actor, &mut conn).await?;
+                    println!("Removed user {}", id);
+                }
+                UserCommand::Assign { id, position } => {
+                    admin::assign(id, position, &actor, &mut conn).await?;
+                    println!("Assigned user {} to position {}", id, position);
+                }
+                UserCommand::Unassign { id, position } => {
+                    admin::unassign(id, position, &actor, &mut conn).await?;
+                    println!("Unassigned user {} from position {}", id, position);
+                }
+            }
+        }
+        Command::Point {
+            database_url,
+            actor,
+            command,
+        } => {
+            let mut conn = db::connect(&database_url).await?;
+            let actor = admin::actor(actor);
+            match command {
+                PointCommand::Add { id, position } => {
+                    let id = admin::add_point(id, position, &actor, &mut conn).await?;
+                    println!("Added point {} at position {}", id, position);
+                }
+                PointCommand::Remove { position } => {
+                    let (id, assignments) =
+                        admin::remove_point(position, &actor, &mut conn).await?;
+                    println!(
+                        "Removed point {} at position {} and {} assignments",
+                        id, position, assignments
+                    );
+                }
+            }
+        }
+        Command::Reader {
+            database_url,
+            actor,
+            command,
+        } => {
+            let mut conn = db::connect(&database_url).await?;
+            let actor = admin::actor(actor);
+            match command {
+                ReaderCommand::Add {
+                    id,
+                    name,
+                    secret,
+                    position,
+                } => {
+                    let (id, secret) = admin::add_reader(
+                        id,
+                        &name,
+                        secret.as_deref(),
+                        &position,
+                        &actor,
+                        &mut conn,
+                    )
+                    .await?;
+                    println!("Added reader {} with secret {}", id, secret);
+                }
+                ReaderCommand::Update {
+                    id,
+                    name,
+                    rotate_secret,
+                } => {
+                    let secret =
+                        admin::update_reader(id, name.as_deref(), rotate_secret, &actor, &mut conn)
+                            .await?;
+                    match secret {
+                        Some(secret) => println!("Updated reader {} with secret {}", id, secret),
+                        None => println!("Updated reader {}", id),
+                    }
+                }
+                ReaderCommand::Remove { id } => {
+                    admin::remove_reader(id, &actor, &mut conn).await?;
+                    println!("Removed reader {}", id);
+                }
+                ReaderCommand::Assign { id, position } => {
+                    admin::assign_reader(id, position, &actor, &mut conn).await?;
+                    println!("Assigned reader {} to position {}", id, position);
+                }
+                ReaderCommand::Unassign { id, position } => {
+                    admin::unassign_reader(id, position, &actor, &mut conn).await?;
+                    println!("Unassigned reader {} from position {}", id, position);
                 }
             }
         }
@@ -174,7 +810,7 @@ async fn main() -> anyhow::Result<()> {
             database_url,
             command,
         } => {
-            let mut conn = SqliteConnection::connect(&database_url).await?;
+            let mut conn = db::connect(&database_url).await?;
             match command {
                 MockCommand::Grant { point, user } => {
                     mock::grant(user, point, &mut conn).await?;
@@ -185,24 +821,114 @@ async fn main() -> anyhow::Result<()> {
                 MockCommand::Swap {} => {
                     mock::swap(&mut conn).await?;
                 }
+                MockCommand::Seed {} => {
+                    mock::seed(&mut conn).await?;
+                }
             }
         }
-        Command::Token { database_url, set } => token::token(&set, &database_url).await?,
+        Command::Report {
+            database_url,
+            filter,
+            command,
+        } => {
+            let mut conn = db::connect(&database_url).await?;
+            match command {
+                ReportCommand::Points { top } => {
+                    report::points(&filter, top, args.format, &mut conn).await?
+                }
+                ReportCommand::Users { top } => {
+                    report::users(&filter, top, args.format, &mut conn).await?
+                }
+                ReportCommand::Timeline { bucket } => {
+                    report::timeline(&filter, bucket, args.format, &mut conn).await?
+                }
+                ReportCommand::DeniedCodes { top } => {
+                    report::denied_codes(&filter, top, args.format, &mut conn).await?
+                }
+            }
+        }
+        Command::Snapshot {
+            database_url,
+            command,
+        } => match command {
+            SnapshotCommand::Export { file } => snapshot::export(&file, &database_url).await?,
+            SnapshotCommand::Import { file } => snapshot::import(&file, &database_url).await?,
+        },
+        Command::Backup {
+            database_url,
+            keep,
+            dir,
+        } => backup::backup(&dir, keep, &database_url).await?,
+        Command::Restore { database_url, file } => backup::restore(&file, &database_url).await?,
+        Command::Migrate {
+            database_url,
+            command,
+        } => match command {
+            Some(MigrateCommand::Status {}) => migrate::status(args.format, &database_url).await?,
+            None => migrate::migrate(&database_url).await?,
+        },
+        Command::Token {
+            database_url,
+            set,
+            hub_id,
+        } => token::token(&set, hub_id.as_deref(), &database_url).await?,
         Command::Heartbeat {
-            access_api_url,
+            cloud,
+            database_url,
+            retention,
+            command,
+        } => {
+            match command {
+                Some(HeartbeatCommand::ExportEvents { file }) => {
+                    return heartbeat::export_events(&file, &database_url).await
+                }
+                Some(HeartbeatCommand::Import { file }) => {
+                    heartbeat::import(&file, &database_url).await?
+                }
+                None => heartbeat::heartbeat(&cloud, &database_url).await?,
+            }
+            // The cloud just acknowledged events, so the retention can drop them.
+            if retention.is_set() {
+                prune::prune(&retention, &database_url).await?
+            }
+        }
+        Command::Prune {
+            database_url,
+            retention,
+        } => prune::prune(&retention, &database_url).await?,
+        Command::Serve {
             database_url,
-        } => heartbeat::heartbeat(&access_api_url, &database_url).await?,
+            service,
+            cloud,
+            retention,
+        } => {
+            let settings = serve::Settings::new(&service, &cloud, &retention);
+            serve::serve(&service, settings, &database_url, || match Cli::parse_with(
+                &defaults.reload()?,
+            )?
+            .command
+            {
+                Command::Serve {
+                    service,
+                    cloud,
+                    retention,
+                    ..
+                } => Ok(serve::Settings::new(&service, &cloud, &retention)),
+                _ => Err(anyhow::anyhow!("Reload outside serve")),
+            })
+            .await?
+        }
         Command::Access {
             code,
             position,
+            deny_untrusted_clock,
             database_url,
-        } => access::access(&code, position, &database_url).await?,
+        } => access::access(&code, position, deny_untrusted_clock, &database_url).await?,
     }
     Ok(())
 }
 
 #[test]
 fn verify_app() {
-    use clap::CommandFactory;
     Cli::command().debug_assert()
 }
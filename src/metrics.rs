@@ -0,0 +1,165 @@
+use crate::outbox;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_histogram, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge, Encoder, Gauge, Histogram, HistogramVec, IntCounterVec, IntGauge,
+};
+use sqlx::SqliteConnection;
+
+lazy_static! {
+    /// Counted from access events, so decisions made by `ahub access` count too.
+    pub static ref ACCESS_DECISIONS: IntCounterVec = register_int_counter_vec!(
+        "ahub_access_decisions_total",
+        "Access decisions by point id, grant or deny and deny reason",
+        &["point", "access", "reason"]
+    )
+    .unwrap();
+    /// Observed by the process deciding, so only decisions made by the service.
+    pub static ref ACCESS_DECISION_DURATION: HistogramVec = register_histogram_vec!(
+        "ahub_access_decision_duration_seconds",
+        "Time to decide access and record the event",
+        &["access"],
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0]
+    )
+    .unwrap();
+    pub static ref HEARTBEATS: IntCounterVec = register_int_counter_vec!(
+        "ahub_heartbeats_total",
+        "Heartbeats by result, success or failure",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref HEARTBEAT_DURATION: Histogram = register_histogram!(
+        "ahub_heartbeat_duration_seconds",
+        "Time to post a heartbeat and apply the response",
+        vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap();
+    static ref LAST_SYNC_AGE: Gauge = register_gauge!(
+        "ahub_last_sync_age_seconds",
+        "Seconds since the last successful heartbeat, NaN before the first"
+    )
+    .unwrap();
+    static ref PENDING_EVENTS: IntGauge = register_int_gauge!(
+        "ahub_pending_events",
+        "Access events waiting for upload to the cloud"
+    )
+    .unwrap();
+    static ref ACTIVE_CODES: IntGauge = register_int_gauge!(
+        "ahub_active_codes",
+        "Codes that open a point now, one per code and point"
+    )
+    .unwrap();
+}
+
+/// Follows the access event table so decision counters start at zero with the service.
+/// Event ids are never reused, so it keeps counting after pruning.
+pub struct EventTail {
+    last_id: i64,
+}
+
+impl EventTail {
+    /// Start after the newest event.
+    pub async fn start(conn: &mut SqliteConnection) -> anyhow::Result<Self> {
+        let (last_id,): (i64,) = sqlx::query_as(r#"select coalesce(max(id), 0) from AccessEvent"#)
+            .fetch_one(&mut *conn)
+            .await?;
+        Ok(Self { last_id })
+    }
+
+    /// Count events inserted since the last call.
+    async fn count_new(&mut self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        let rows: Vec<(i64, String, String, i64, i64)> = sqlx::query_as(
+            r#"select access_point_id, access, coalesce(reason, ''), count(*), max(id)
+            from AccessEvent where id > ? group by access_point_id, access, reason"#,
+        )
+        .bind(self.last_id)
+        .fetch_all(&mut *conn)
+        .await?;
+        for (point, access, reason, count, max_id) in rows {
+            ACCESS_DECISIONS
+                .with_label_values(&[&point.to_string(), &access, &reason])
+                .inc_by(count as u64);
+            self.last_id = self.last_id.max(max_id);
+        }
+        Ok(())
+    }
+}
+
+/// Update the counters and gauges that come from the database and encode all metrics in
+/// the Prometheus text format.
+pub async fn gather(tail: &mut EventTail, conn: &mut SqliteConnection) -> anyhow::Result<String> {
+    tail.count_new(&mut *conn).await?;
+
+    let (last_sync_age,): (Option<f64>,) = sqlx::query_as(
+        r#"select (julianday('now') - julianday(max(ended_at))) * 86400 from SyncRun
+        where ended_at is not null and error is null"#,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    LAST_SYNC_AGE.set(last_sync_age.unwrap_or(f64::NAN));
+
+    let (pending_events,): (i64,) =
+        sqlx::query_as(r#"select count(*) from Outbox where kind = ? and delivered_at is null"#)
+            .bind(outbox::EVENT)
+            .fetch_one(&mut *conn)
+            .await?;
+    PENDING_EVENTS.set(pending_events);
+
+    let (active_codes,): (i64,) = sqlx::query_as(r#"select count(*) from ActiveCode"#)
+        .fetch_one(&mut *conn)
+        .await?;
+    ACTIVE_CODES.set(active_codes);
+
+    let mut buffer = Vec::<u8>::new();
+    prometheus::TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[tokio::test]
+async fn test_gather() {
+    let mut conn = crate::db::test_conn().await;
+    sqlx::query(
+        r#"insert into AccessEvent (at, access, code, access_point_id) values ('2022-01-01 00:00:00', 'grant', '111', 1)"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let mut tail = EventTail::start(&mut conn).await.unwrap();
+    sqlx::query(
+        r#"insert into AccessEvent (at, access, code, access_point_id, reason) values
+          ('2022-01-02 00:00:00', 'deny', '999', 4, 'code'),
+          ('2022-01-03 00:00:00', 'deny', '998', 4, 'code');"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+
+    let text = gather(&mut tail, &mut conn).await.unwrap();
+    assert!(
+        text.contains(r#"ahub_access_decisions_total{access="deny",point="4",reason="code"} 2"#)
+    );
+    assert!(!text.contains(r#"access="grant",point="1""#));
+    assert!(text.contains("ahub_pending_events 3"));
+    assert!(text.contains("ahub_last_sync_age_seconds NaN"));
+}
+
+#[tokio::test]
+async fn test_event_tail_after_prune() {
+    let mut conn = crate::db::test_conn().await;
+    let mut tail = EventTail::start(&mut conn).await.unwrap();
+    let decisions = ACCESS_DECISIONS.with_label_values(&["3", "deny", "clock"]);
+    let insert = r#"insert into AccessEvent (at, access, code, access_point_id, reason)
+        values (CURRENT_TIMESTAMP, 'deny', '111', 3, 'clock')"#;
+    sqlx::query(insert).execute(&mut conn).await.unwrap();
+    tail.count_new(&mut conn).await.unwrap();
+    assert_eq!(decisions.get(), 1);
+
+    // Pruning left no events behind.
+    sqlx::query(r#"delete from AccessEvent"#)
+        .execute(&mut conn)
+        .await
+        .unwrap();
+    sqlx::query(insert).execute(&mut conn).await.unwrap();
+    tail.count_new(&mut conn).await.unwrap();
+    assert_eq!(decisions.get(), 2);
+}
@@ -0,0 +1,160 @@
+use crate::db;
+use crate::format::{self, Format, Tabular};
+use serde::Serialize;
+use sqlx::{ConnectOptions, SqliteConnection};
+use std::collections::HashMap;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MigrationStatus {
+    version: i64,
+    description: String,
+    /// applied, pending, changed when the embedded sql differs from the applied one, failed,
+    /// or unknown when only the database has it
+    state: String,
+}
+
+/// Apply the embedded migrations, creating the database file if missing.
+pub async fn migrate(database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::options(database_url)?
+        .create_if_missing(true)
+        .connect()
+        .await?;
+    let before = db::applied_version(&mut conn).await?;
+    sqlx::migrate!().run(&mut conn).await?;
+    let after = db::applied_version(&mut conn).await?;
+    if before == after {
+        println!("Schema is up to date at version {}", db::schema_version());
+    } else {
+        println!(
+            "Migrated schema from version {} to {}",
+            before
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "none".into()),
+            db::schema_version()
+        );
+    }
+    Ok(())
+}
+
+/// Print embedded and applied migrations.
+pub async fn status(format: Format, database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::options(database_url)?.connect().await?;
+    format::print(format, &migration_statuses(&mut conn).await?)
+}
+
+async fn migration_statuses(conn: &mut SqliteConnection) -> anyhow::Result<Vec<MigrationStatus>> {
+    let mut applied = HashMap::<i64, (String, bool, Vec<u8>)>::new();
+    if db::applied_version(conn).await?.is_some() {
+        let rows: Vec<(i64, String, bool, Vec<u8>)> = sqlx::query_as(
+            r#"select version, description, success, checksum from _sqlx_migrations"#,
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        for (version, description, success, checksum) in rows {
+            applied.insert(version, (description, success, checksum));
+        }
+    }
+
+    let mut statuses = Vec::<MigrationStatus>::new();
+    for m in sqlx::migrate!().migrations.iter() {
+        let state = match applied.remove(&m.version) {
+            None => "pending",
+            Some((_, false, _)) => "failed",
+            Some((_, true, checksum)) if checksum != *m.checksum => "changed",
+            Some(_) => "applied",
+        };
+        statuses.push(MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            state: state.into(),
+        });
+    }
+    for (version, (description, _, _)) in applied {
+        statuses.push(MigrationStatus {
+            version,
+            description,
+            state: "unknown".into(),
+        });
+    }
+    statuses.sort_by_key(|s| s.version);
+    Ok(statuses)
+}
+
+impl Tabular for MigrationStatus {
+    fn headers() -> Vec<&'static str> {
+        vec!["version", "description", "state"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.version.to_string(),
+            self.description.clone(),
+            self.state.clone(),
+        ]
+    }
+}
+
+#[tokio::test]
+async fn test_migration_statuses() {
+    let mut conn = db::options("sqlite::memory:")
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let statuses = migration_statuses(&mut conn).await.unwrap();
+    assert!(statuses.iter().all(|s| s.state == "pending"));
+
+    sqlx::migrate!().run(&mut conn).await.unwrap();
+    sqlx::query(
+        r#"update _sqlx_migrations set checksum = x'00' where version = (select min(version) from _sqlx_migrations)"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let states: Vec<String> = migration_statuses(&mut conn)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|s| s.state)
+        .collect();
+    assert_eq!(states[0], "changed");
+    assert!(states[1..].iter().all(|s| s == "applied"));
+}
+
+#[tokio::test]
+async fn test_remove_dev_seed() {
+    use sqlx::Executor;
+    let mut conn = db::options("sqlite::memory:")
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let migrations = sqlx::migrate!().migrations;
+    for m in migrations.iter().filter(|m| m.version < 20220413120000) {
+        conn.execute(&*m.sql).await.unwrap();
+    }
+    sqlx::query(
+        r#"insert into AccessUser (id, code) values (10, '111');
+        insert into AccessPointToAccessUser (access_point_id, access_user_id) values (2, 10);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let m = migrations
+        .iter()
+        .find(|m| m.version == 20220413120000)
+        .unwrap();
+    conn.execute(&*m.sql).await.unwrap();
+
+    let hubs: Vec<(String,)> = sqlx::query_as(r#"select id from AccessHub"#)
+        .fetch_all(&mut conn)
+        .await
+        .unwrap();
+    assert!(hubs.is_empty());
+    // The point a user was given stays.
+    let points: Vec<(i64,)> = sqlx::query_as(r#"select id from AccessPoint"#)
+        .fetch_all(&mut conn)
+        .await
+        .unwrap();
+    assert_eq!(points, vec![(2,)]);
+}
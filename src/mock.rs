@@ -2,6 +2,23 @@ use crate::domain::{Event, User};
 use anyhow::Context;
 use sqlx::SqliteConnection;
 
+/// Development hub and points 1-4, which the tests also run against.
+pub const DEV_SEED: &str = include_str!("../fixtures/dev_seed.sql");
+
+/// Add the development hub and points to a new database. Its id and token are the same
+/// everywhere, so a real hub is provisioned with `token --hub-id` instead.
+pub async fn seed(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let (hubs,): (i64,) = sqlx::query_as(r#"select count(*) from AccessHub"#)
+        .fetch_one(&mut *conn)
+        .await?;
+    if hubs > 0 {
+        return Err(anyhow::anyhow!("Database already has a hub"));
+    }
+    sqlx::query(DEV_SEED).execute(&mut *conn).await?;
+    println!("Added the development hub and points 1-4");
+    Ok(())
+}
+
 pub async fn grant(user_id: i64, point_id: i64, conn: &mut SqliteConnection) -> anyhow::Result<()> {
     let user = sqlx::query_as::<_, User>(
         r#"
@@ -105,7 +122,7 @@ pub async fn swap(conn: &mut SqliteConnection) -> anyhow::Result<()> {
 async fn print_event(id: i64, conn: &mut SqliteConnection) -> anyhow::Result<()> {
     let event = sqlx::query_as::<_, Event>(
         r#"
-select id, at, access, code, access_user_id, access_point_id from AccessEvent where id = ?"#,
+select id, at, access, code, access_user_id, access_point_id, reason, access_reader_id from AccessEvent where id = ?"#,
     )
     .bind(id)
     .fetch_one(&mut *conn)
@@ -0,0 +1,110 @@
+use sqlx::SqliteConnection;
+
+/// Outbox kind of rows queued by the AccessEvent insert trigger.
+pub const EVENT: &str = "event";
+/// Outbox kind of hub alerts. Payload is the JSON sent to the cloud.
+pub const ALERT: &str = "alert";
+
+pub async fn enqueue(
+    kind: &str,
+    payload: &str,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<i64> {
+    let id = sqlx::query(r#"insert into Outbox (kind, payload) values (?, ?)"#)
+        .bind(kind)
+        .bind(payload)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    Ok(id)
+}
+
+/// Record a delivery attempt for outbox rows. Error is None when the cloud responded.
+pub async fn record_attempt(
+    ids: &[i64],
+    error: Option<&str>,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let query = format!(
+        "update Outbox set attempts = attempts + 1, last_attempt_at = CURRENT_TIMESTAMP, last_error = ? where id in ({})",
+        ids.iter().map(|_| "?").collect::<Vec<&str>>().join(", ")
+    );
+    let mut q = sqlx::query(&query).bind(error);
+    for id in ids.iter() {
+        q = q.bind(id);
+    }
+    let rows_affected = q.execute(&mut *conn).await?.rows_affected();
+    if rows_affected as usize != ids.len() {
+        return Err(anyhow::anyhow!(
+            "Record outbox attempt affected {} rows instead of {}.",
+            rows_affected,
+            ids.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Mark rows delivered once the cloud accepted the request that carried them.
+pub async fn acknowledge(ids: &[i64], conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let query = format!(
+        "update Outbox set delivered_at = CURRENT_TIMESTAMP, last_error = null where id in ({})",
+        ids.iter().map(|_| "?").collect::<Vec<&str>>().join(", ")
+    );
+    let mut q = sqlx::query(&query);
+    for id in ids.iter() {
+        q = q.bind(id);
+    }
+    q.execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// Mark event rows delivered once the cloud cursor covers them. The cursor is the cloud's
+/// acknowledgement, so it also covers events that reached the cloud in an export or in a
+/// heartbeat whose response was lost, not only the rows of the last request.
+pub async fn acknowledge_events(
+    cloud_last_access_event_at: chrono::NaiveDateTime,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<u64> {
+    let delivered = sqlx::query(
+        r#"update Outbox set delivered_at = CURRENT_TIMESTAMP, last_error = null
+        where kind = ? and delivered_at is null
+          and record_id in (select id from AccessEvent where at <= ?)"#,
+    )
+    .bind(EVENT)
+    .bind(cloud_last_access_event_at)
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    Ok(delivered)
+}
+
+/// Note on sent rows the cloud cursor did not cover that the cloud did not acknowledge them.
+/// They stay pending and are retried on the next heartbeat.
+pub async fn record_unacknowledged(
+    sent_ids: &[i64],
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    if sent_ids.is_empty() {
+        return Ok(());
+    }
+    let query = format!(
+        "update Outbox set last_error = ? where delivered_at is null and id in ({})",
+        sent_ids
+            .iter()
+            .map(|_| "?")
+            .collect::<Vec<&str>>()
+            .join(", ")
+    );
+    let mut q = sqlx::query(&query).bind("Not acknowledged by cloud");
+    for id in sent_ids.iter() {
+        q = q.bind(id);
+    }
+    q.execute(&mut *conn).await?;
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use crate::db;
+use crate::outbox;
+use sqlx::{Connection, SqliteConnection};
+
+/// Retention of access events the cloud has acknowledged. Events still waiting for upload
+/// are never pruned.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Retention {
+    /// Prune uploaded events older than this many days
+    #[clap(long, env, parse(try_from_str))]
+    pub retention_days: Option<i64>,
+
+    /// Prune uploaded events beyond the newest this many events
+    #[clap(long, env, parse(try_from_str))]
+    pub retention_rows: Option<i64>,
+
+    /// Reclaim the pruned pages with an incremental vacuum
+    #[clap(long, env = "RETENTION_VACUUM")]
+    pub vacuum: bool,
+}
+
+impl Retention {
+    pub fn is_set(&self) -> bool {
+        self.retention_days.is_some() || self.retention_rows.is_some()
+    }
+}
+
+pub async fn prune(retention: &Retention, database_url: &str) -> anyhow::Result<()> {
+    if !retention.is_set() {
+        return Err(anyhow::anyhow!(
+            "Missing retention: pass --retention-days or --retention-rows"
+        ));
+    }
+    let mut conn = db::connect(database_url).await?;
+    let (events, outbox_rows) =
+        prune_events(retention, chrono::Utc::now().naive_utc(), &mut conn).await?;
+    println!(
+        "Pruned {} uploaded events and {} outbox rows",
+        events, outbox_rows
+    );
+    if retention.vacuum {
+        vacuum(&mut conn).await?;
+    }
+    Ok(())
+}
+
+/// Delete acknowledged events outside the retention and their delivered outbox rows.
+async fn prune_events(
+    retention: &Retention,
+    now: chrono::NaiveDateTime,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<(u64, u64)> {
+    let before = retention
+        .retention_days
+        .map(|days| now - chrono::Duration::days(days));
+    let mut tx = conn.begin().await?;
+    let events = sqlx::query(
+        r#"delete from AccessEvent where id in (
+            select e.id from AccessEvent e
+            join Outbox o on o.kind = ?1 and o.record_id = e.id and o.delivered_at is not null
+            where (?2 is not null and e.at < ?2)
+              or (?3 is not null and e.id not in (select id from AccessEvent order by id desc limit coalesce(?3, -1))))"#,
+    )
+    .bind(outbox::EVENT)
+    .bind(before)
+    .bind(retention.retention_rows)
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    let outbox_rows = sqlx::query(
+        r#"delete from Outbox where kind = ? and delivered_at is not null
+        and record_id not in (select id from AccessEvent)"#,
+    )
+    .bind(outbox::EVENT)
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    Ok((events, outbox_rows))
+}
+
+/// Free pages left by pruning. A database created without incremental auto vacuum is
+/// converted once with a full vacuum.
+async fn vacuum(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let (auto_vacuum,): (i64,) = sqlx::query_as(r#"pragma auto_vacuum"#)
+        .fetch_one(&mut *conn)
+        .await?;
+    if auto_vacuum != 2 {
+        tracing::info!("Converting database to incremental auto vacuum with a full vacuum");
+        sqlx::query(r#"pragma auto_vacuum = incremental"#)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(r#"vacuum"#).execute(&mut *conn).await?;
+    } else {
+        sqlx::query(r#"pragma incremental_vacuum"#)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_events() {
+    let mut conn = db::test_conn().await;
+    sqlx::query(
+        r#"insert into AccessEvent (id, at, access, code, access_point_id) values
+          (1, '2022-01-01 00:00:00', 'grant', '111', 1),
+          (2, '2022-01-02 00:00:00', 'grant', '111', 1),
+          (3, '2022-01-09 00:00:00', 'grant', '111', 1),
+          (4, '2022-01-10 00:00:00', 'grant', '111', 1);
+        update Outbox set delivered_at = CURRENT_TIMESTAMP where record_id in (2, 3, 4);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let now = chrono::NaiveDate::from_ymd(2022, 1, 10).and_hms(0, 0, 0);
+    async fn ids(conn: &mut SqliteConnection) -> Vec<(i64,)> {
+        sqlx::query_as(r#"select id from AccessEvent order by id"#)
+            .fetch_all(conn)
+            .await
+            .unwrap()
+    }
+
+    let days = Retention {
+        retention_days: Some(7),
+        ..Default::default()
+    };
+    assert_eq!(prune_events(&days, now, &mut conn).await.unwrap(), (1, 1));
+    assert_eq!(ids(&mut conn).await, vec![(1,), (3,), (4,)]);
+
+    let rows = Retention {
+        retention_rows: Some(1),
+        ..Default::default()
+    };
+    assert_eq!(prune_events(&rows, now, &mut conn).await.unwrap(), (1, 1));
+    // Event 1 was never uploaded.
+    assert_eq!(ids(&mut conn).await, vec![(1,), (4,)]);
+}
+
+#[tokio::test]
+async fn test_prune_all_events_keeps_ids_growing() {
+    let mut conn = db::test_conn().await;
+    sqlx::query(
+        r#"insert into AccessEvent (id, at, access, code, access_point_id) values
+          (1, '2022-01-01 00:00:00', 'grant', '111', 1),
+          (2, '2022-01-02 00:00:00', 'grant', '111', 1);
+        update Outbox set delivered_at = CURRENT_TIMESTAMP;"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let days = Retention {
+        retention_days: Some(7),
+        ..Default::default()
+    };
+    let now = chrono::NaiveDate::from_ymd(2022, 1, 10).and_hms(0, 0, 0);
+    assert_eq!(prune_events(&days, now, &mut conn).await.unwrap(), (2, 2));
+
+    let id = sqlx::query(
+        r#"insert into AccessEvent (at, access, code, access_point_id) values (CURRENT_TIMESTAMP, 'grant', '111', 1)"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap()
+    .last_insert_rowid();
+    assert_eq!(id, 3);
+}
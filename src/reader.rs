@@ -0,0 +1,229 @@
+use crate::access;
+use crate::api::{self, ApiError};
+use crate::db;
+use crate::domain::Reader;
+use crate::serve::State;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use sqlx::SqliteConnection;
+use tracing::{info, warn};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessBody {
+    code: String,
+    position: i64,
+}
+
+/// Answer `POST /access {code, position}` with `{access, reason}` like `ahub access`. The
+/// reader sends its secret as `Authorization: Bearer <secret>` and may only ask for the
+/// positions it is assigned to.
+pub async fn route(state: &State, req: Request<Body>) -> Result<Response<Body>, ApiError> {
+    if req.method() != Method::POST {
+        return Err(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "Use POST"));
+    }
+    // The body is only read from readers the hub knows.
+    let reader = match api::bearer(&req) {
+        Some(secret) => authenticate(secret, &mut *state.conn.lock().await).await?,
+        None => None,
+    }
+    .ok_or_else(|| {
+        warn!("Access request with missing or unknown reader secret");
+        ApiError::new(StatusCode::UNAUTHORIZED, "Missing or unknown reader secret")
+    })?;
+    let body: AccessBody = api::body(req).await?;
+
+    let mut conn = state.conn.lock().await;
+    if !may_ask(reader.id, body.position, &mut conn).await? {
+        warn!(reader = %reader.name, position = body.position, "Reader asked for another position");
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!(
+                "Reader {} may not ask for position {}",
+                reader.name, body.position
+            ),
+        ));
+    }
+    let decision = access::decide(
+        &body.code,
+        body.position,
+        Some(reader.id),
+        state.settings().deny_untrusted_clock,
+        &mut conn,
+    )
+    .await?;
+    info!(reader = %reader.name, position = body.position, access = decision.access, "Access decided");
+    Ok(api::json_response(StatusCode::OK, &decision))
+}
+
+/// Hex SHA-256 of a reader secret, which the hub stores instead of the secret. Secrets are
+/// random and long, so a fast unsalted hash is enough.
+pub fn secret_hash(secret: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Reader with secret, looked up by its hash, which is marked as seen. The door waits on
+/// this like on the event, so the write is retried the same way.
+async fn authenticate(secret: &str, conn: &mut SqliteConnection) -> anyhow::Result<Option<Reader>> {
+    let reader = sqlx::query_as::<_, Reader>(
+        r#"select id, name, last_seen_at from AccessReader where secret_hash = ?"#,
+    )
+    .bind(secret_hash(secret))
+    .fetch_optional(&mut *conn)
+    .await?;
+    let reader = match reader {
+        Some(reader) => reader,
+        None => return Ok(None),
+    };
+    let mut attempt = 0;
+    loop {
+        let result =
+            sqlx::query(r#"update AccessReader set last_seen_at = current_timestamp where id = ?"#)
+                .bind(reader.id)
+                .execute(&mut *conn)
+                .await;
+        match result {
+            Ok(_) => return Ok(Some(reader)),
+            Err(err) if db::is_busy(&err) && attempt < db::BUSY_RETRIES => {
+                attempt += 1;
+                warn!(
+                    attempt,
+                    retries = db::BUSY_RETRIES,
+                    "Database busy, retrying"
+                );
+                tokio::time::sleep(db::busy_backoff(attempt)).await;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+async fn may_ask(
+    reader_id: i64,
+    position: i64,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<bool> {
+    let (assigned,): (bool,) = sqlx::query_as(
+        r#"select count(*) > 0 from AccessPointToAccessReader r
+        join AccessPoint p on r.access_point_id = p.id
+        where r.access_reader_id = ? and p.position = ?"#,
+    )
+    .bind(reader_id)
+    .bind(position)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(assigned)
+}
+
+#[tokio::test]
+async fn test_reader() {
+    let mut conn = crate::db::test_conn().await;
+    sqlx::query(
+        r#"insert into AccessUser (id, code) values (10, '555');
+        insert into AccessPointToAccessUser (access_point_id, access_user_id) values (1, 10), (2, 10);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    crate::admin::add_reader(
+        None,
+        "front-door",
+        Some("0123456789abcdef"),
+        &[1],
+        "test",
+        &mut conn,
+    )
+    .await
+    .unwrap();
+    let (id, _) = crate::admin::add_reader(None, "back-door", None, &[2], "test", &mut conn)
+        .await
+        .unwrap();
+    crate::admin::remove_reader(id, "test", &mut conn)
+        .await
+        .unwrap();
+    // Ids are not reused, so old events do not point at the new reader.
+    let (id, _) = crate::admin::add_reader(None, "back-door", None, &[2], "test", &mut conn)
+        .await
+        .unwrap();
+    assert_eq!(id, 3);
+    assert!(
+        crate::admin::add_reader(None, "front-door", None, &[2], "test", &mut conn)
+            .await
+            .is_err()
+    );
+    assert!(
+        crate::admin::add_reader(None, "side-door", Some("short"), &[3], "test", &mut conn)
+            .await
+            .is_err()
+    );
+    let settings = crate::serve::Settings {
+        cloud: crate::heartbeat::Cloud {
+            access_api_url: None,
+            max_clock_skew: 30,
+            timeout: 30,
+            batch_size: None,
+        },
+        retention: Default::default(),
+        admin_token: None,
+        deny_untrusted_clock: false,
+    };
+    let state = State::new(conn, "sqlite::memory:", settings).await.unwrap();
+    let request = |secret: &str, body: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/access")
+            .header(hyper::header::AUTHORIZATION, format!("Bearer {}", secret))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = route(
+        &state,
+        request("0123456789abcdef", r#"{"code": "555", "position": 1}"#),
+    )
+    .await
+    .unwrap();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(bytes, r#"{"access":"grant","reason":null}"#);
+
+    // A valid code at the door of another reader.
+    let err = route(
+        &state,
+        request("0123456789abcdef", r#"{"code": "555", "position": 2}"#),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.status, StatusCode::FORBIDDEN);
+    let err = route(
+        &state,
+        request("0123456789abcdeX", r#"{"code": "555", "position": 1}"#),
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+    let err = route(&state, request("0123456789abcdef", &" ".repeat(65 * 1024)))
+        .await
+        .unwrap_err();
+    assert_eq!(err.status, StatusCode::PAYLOAD_TOO_LARGE);
+    // Unknown readers are turned away before their body is read.
+    let err = route(&state, request("0123456789abcdeX", &" ".repeat(65 * 1024)))
+        .await
+        .unwrap_err();
+    assert_eq!(err.status, StatusCode::UNAUTHORIZED);
+
+    let mut conn = state.conn.lock().await;
+    let events: Vec<(i64, Option<i64>)> =
+        sqlx::query_as(r#"select access_point_id, access_reader_id from AccessEvent"#)
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+    assert_eq!(events, vec![(1, Some(1))]);
+    let readers = crate::dump::select_readers(&mut conn).await.unwrap();
+    assert_eq!(readers[0].reader.name, "back-door");
+    assert!(readers[0].reader.last_seen_at.is_none());
+    assert!(readers[1].reader.last_seen_at.is_some());
+}
@@ -0,0 +1,296 @@
+use crate::events::{bind_filter, EventFilter, FILTER_CONDITIONS};
+use crate::format::{self, timestamp, Format, Tabular};
+use crate::json::json_naive_date_time;
+use serde::Serialize;
+use sqlx::SqliteConnection;
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+pub enum Bucket {
+    Hour,
+    Day,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+struct PointCount {
+    access_point_id: i64,
+    position: i64,
+    grants: i64,
+    denies: i64,
+    total: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+struct UserCount {
+    access_user_id: i64,
+    grants: i64,
+    denies: i64,
+    total: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+struct BucketCount {
+    #[serde(with = "json_naive_date_time")]
+    bucket: chrono::NaiveDateTime,
+    grants: i64,
+    denies: i64,
+    total: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+struct DeniedCode {
+    code: String,
+    denies: i64,
+    #[serde(with = "json_naive_date_time")]
+    last_at: chrono::NaiveDateTime,
+}
+
+const COUNTS: &str = r#"sum(access = 'grant') as grants, sum(access = 'deny') as denies,
+    count(*) as total"#;
+
+pub async fn points(
+    filter: &EventFilter,
+    top: i32,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    format::print(format, &select_points(filter, top, conn).await?)
+}
+
+/// Grant and deny counts per point, busiest first.
+async fn select_points(
+    filter: &EventFilter,
+    top: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<PointCount>> {
+    let query = format!(
+        r#"select e.access_point_id, p.position, {}
+        from AccessEvent e join AccessPoint p on e.access_point_id = p.id
+        where {} group by e.access_point_id order by total desc, p.position asc limit ?9"#,
+        COUNTS, FILTER_CONDITIONS
+    );
+    Ok(bind_filter(sqlx::query_as::<_, PointCount>(&query), filter)
+        .bind(top)
+        .fetch_all(&mut *conn)
+        .await?)
+}
+
+pub async fn users(
+    filter: &EventFilter,
+    top: i32,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    format::print(format, &select_users(filter, top, conn).await?)
+}
+
+/// Grant and deny counts per user, busiest first. Denied codes without a user are left out.
+async fn select_users(
+    filter: &EventFilter,
+    top: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<UserCount>> {
+    let query = format!(
+        r#"select access_user_id, {} from AccessEvent
+        where access_user_id is not null and {}
+        group by access_user_id order by total desc, access_user_id asc limit ?9"#,
+        COUNTS, FILTER_CONDITIONS
+    );
+    Ok(bind_filter(sqlx::query_as::<_, UserCount>(&query), filter)
+        .bind(top)
+        .fetch_all(&mut *conn)
+        .await?)
+}
+
+pub async fn timeline(
+    filter: &EventFilter,
+    bucket: Bucket,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    format::print(format, &select_timeline(filter, bucket, conn).await?)
+}
+
+/// Grant and deny counts per hour or day, oldest first.
+async fn select_timeline(
+    filter: &EventFilter,
+    bucket: Bucket,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<BucketCount>> {
+    let bucket = match bucket {
+        Bucket::Hour => "strftime('%Y-%m-%d %H:00:00', at)",
+        Bucket::Day => "strftime('%Y-%m-%d 00:00:00', at)",
+    };
+    let query = format!(
+        r#"select {} as bucket, {} from AccessEvent where {} group by bucket order by bucket asc"#,
+        bucket, COUNTS, FILTER_CONDITIONS
+    );
+    Ok(
+        bind_filter(sqlx::query_as::<_, BucketCount>(&query), filter)
+            .fetch_all(&mut *conn)
+            .await?,
+    )
+}
+
+pub async fn denied_codes(
+    filter: &EventFilter,
+    top: i32,
+    format: Format,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    format::print(format, &select_denied_codes(filter, top, conn).await?)
+}
+
+/// Most denied codes.
+async fn select_denied_codes(
+    filter: &EventFilter,
+    top: i32,
+    conn: &mut SqliteConnection,
+) -> anyhow::Result<Vec<DeniedCode>> {
+    let query = format!(
+        r#"select code, count(*) as denies, max(at) as last_at from AccessEvent
+        where access = 'deny' and {} group by code order by denies desc, last_at desc limit ?9"#,
+        FILTER_CONDITIONS
+    );
+    Ok(bind_filter(sqlx::query_as::<_, DeniedCode>(&query), filter)
+        .bind(top)
+        .fetch_all(&mut *conn)
+        .await?)
+}
+
+impl Tabular for PointCount {
+    fn headers() -> Vec<&'static str> {
+        vec!["accessPointId", "position", "grants", "denies", "total"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.access_point_id.to_string(),
+            self.position.to_string(),
+            self.grants.to_string(),
+            self.denies.to_string(),
+            self.total.to_string(),
+        ]
+    }
+}
+
+impl Tabular for UserCount {
+    fn headers() -> Vec<&'static str> {
+        vec!["accessUserId", "grants", "denies", "total"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.access_user_id.to_string(),
+            self.grants.to_string(),
+            self.denies.to_string(),
+            self.total.to_string(),
+        ]
+    }
+}
+
+impl Tabular for BucketCount {
+    fn headers() -> Vec<&'static str> {
+        vec!["bucket", "grants", "denies", "total"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            timestamp(&self.bucket),
+            self.grants.to_string(),
+            self.denies.to_string(),
+            self.total.to_string(),
+        ]
+    }
+}
+
+impl Tabular for DeniedCode {
+    fn headers() -> Vec<&'static str> {
+        vec!["code", "denies", "lastAt"]
+    }
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.code.clone(),
+            self.denies.to_string(),
+            timestamp(&self.last_at),
+        ]
+    }
+}
+
+#[tokio::test]
+async fn test_reports() {
+    let mut conn = crate::db::test_conn().await;
+    sqlx::query(
+        r#"insert into AccessEvent (id, at, access, code, access_user_id, access_point_id,
+            access_reader_id)
+        values (1, '2022-03-31 23:00:00', 'grant', '111', 10, 1, 1),
+          (2, '2022-04-01 08:10:00', 'grant', '111', 10, 1, 1),
+          (3, '2022-04-01 08:20:00', 'grant', '111', 10, 1, 1),
+          (4, '2022-04-01 08:30:00', 'deny', '999', null, 1, 1),
+          (5, '2022-04-01 09:05:00', 'grant', '222', 20, 2, 1),
+          (6, '2022-04-01 09:10:00', 'deny', '999', null, 2, 1),
+          (7, '2022-04-01 09:15:00', 'deny', '888', null, 3, 1),
+          (8, '2022-04-01 09:20:00', 'grant', '333', 30, 3, 2),
+          (9, '2022-04-01 09:25:00', 'deny', '777', null, 4, 2);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    // Since is the first placeholder and reader the last of the filter, right before the limit.
+    let filter = EventFilter {
+        since: Some(chrono::NaiveDate::from_ymd(2022, 4, 1).and_hms(0, 0, 0)),
+        reader: Some(1),
+        ..Default::default()
+    };
+    let at = |h, m| chrono::NaiveDate::from_ymd(2022, 4, 1).and_hms(h, m, 0);
+
+    let points = select_points(&filter, 2, &mut conn).await.unwrap();
+    assert_eq!(
+        points
+            .iter()
+            .map(|p| (p.position, p.grants, p.denies, p.total))
+            .collect::<Vec<_>>(),
+        vec![(1, 2, 1, 3), (2, 1, 1, 2)]
+    );
+    let all = select_points(&EventFilter::default(), 10, &mut conn)
+        .await
+        .unwrap();
+    assert_eq!(all.len(), 4);
+    assert_eq!((all[0].position, all[0].total), (1, 4));
+
+    let users = select_users(&filter, 1, &mut conn).await.unwrap();
+    assert_eq!(
+        users
+            .iter()
+            .map(|u| (u.access_user_id, u.grants, u.denies, u.total))
+            .collect::<Vec<_>>(),
+        vec![(10, 2, 0, 2)]
+    );
+
+    let timeline = select_timeline(&filter, Bucket::Hour, &mut conn)
+        .await
+        .unwrap();
+    assert_eq!(
+        timeline
+            .iter()
+            .map(|b| (b.bucket, b.grants, b.denies, b.total))
+            .collect::<Vec<_>>(),
+        vec![(at(8, 0), 2, 1, 3), (at(9, 0), 1, 2, 3)]
+    );
+    let days = select_timeline(&filter, Bucket::Day, &mut conn)
+        .await
+        .unwrap();
+    assert_eq!(
+        days.iter().map(|b| (b.bucket, b.total)).collect::<Vec<_>>(),
+        vec![(at(0, 0), 6)]
+    );
+
+    let codes = select_denied_codes(&filter, 1, &mut conn).await.unwrap();
+    assert_eq!(
+        codes
+            .iter()
+            .map(|c| (c.code.as_str(), c.denies, c.last_at))
+            .collect::<Vec<_>>(),
+        vec![("999", 2, at(9, 10))]
+    );
+}
@@ -0,0 +1,390 @@
+use crate::api;
+use crate::db;
+use crate::heartbeat;
+use crate::metrics;
+use crate::prune;
+use crate::reader;
+use crate::systemd;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use sqlx::SqliteConnection;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, warn};
+
+/// Long-running hub with HTTP endpoints and an optional heartbeat loop.
+#[derive(clap::Args, Debug)]
+pub struct Service {
+    /// Address the HTTP endpoints listen on
+    #[clap(long, env = "AHUB_LISTEN", default_value = "127.0.0.1:9100")]
+    pub listen: SocketAddr,
+
+    /// Seconds between heartbeats, by default the service does not post heartbeats
+    #[clap(long, env, parse(try_from_str))]
+    pub heartbeat_interval: Option<u64>,
+
+    /// Bearer token of the /api endpoints, which are disabled without one
+    #[clap(long, env = "AHUB_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Deny codes with activate or expire times while heartbeat reports excessive clock skew
+    #[clap(long, env)]
+    pub deny_untrusted_clock: bool,
+}
+
+/// Settings a reload replaces. The listen address, database and heartbeat interval need a
+/// restart.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub cloud: heartbeat::Cloud,
+    pub retention: prune::Retention,
+    pub admin_token: Option<String>,
+    pub deny_untrusted_clock: bool,
+}
+
+impl Settings {
+    pub fn new(service: &Service, cloud: &heartbeat::Cloud, retention: &prune::Retention) -> Self {
+        Self {
+            cloud: cloud.clone(),
+            retention: retention.clone(),
+            admin_token: service.admin_token.clone(),
+            deny_untrusted_clock: service.deny_untrusted_clock,
+        }
+    }
+}
+
+/// Shared by requests. One connection serializes them, which a hub's load allows.
+pub struct State {
+    pub conn: Mutex<SqliteConnection>,
+    pub tail: Mutex<metrics::EventTail>,
+    pub database_url: String,
+    settings: RwLock<Settings>,
+    /// Held while a heartbeat runs, so the loop and `POST /api/heartbeat` take turns
+    pub heartbeat: Mutex<()>,
+    /// When the heartbeat loop last finished a heartbeat, for the watchdog
+    heartbeat_at: std::sync::Mutex<Instant>,
+}
+
+impl State {
+    pub async fn new(
+        mut conn: SqliteConnection,
+        database_url: &str,
+        settings: Settings,
+    ) -> anyhow::Result<Self> {
+        let tail = metrics::EventTail::start(&mut conn).await?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            tail: Mutex::new(tail),
+            database_url: database_url.to_string(),
+            settings: RwLock::new(settings),
+            heartbeat: Mutex::new(()),
+            heartbeat_at: std::sync::Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Current settings, copied so no lock is held across an await.
+    pub fn settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Post one heartbeat and prune after it when retention is set, for the heartbeat loop
+    /// and `POST /api/heartbeat` alike. Callers hold the heartbeat lock. A failed prune is
+    /// only logged, since the heartbeat went through.
+    pub async fn sync(
+        &self,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<heartbeat::SyncRunCounts> {
+        let settings = self.settings();
+        let counts = heartbeat::run(&settings.cloud, conn).await?;
+        if settings.retention.is_set() {
+            if let Err(err) = prune::prune(&settings.retention, &self.database_url).await {
+                error!("Prune failed: {:#}", err);
+            }
+        }
+        Ok(counts)
+    }
+}
+
+/// Run until SIGTERM or SIGINT, then stop taking connections, finish requests and the
+/// heartbeat in flight and return. SIGHUP reloads the settings with reload. Under systemd
+/// with `Type=notify` readiness, reloads and stopping are reported and the watchdog is
+/// pinged while requests and heartbeats make progress.
+pub async fn serve(
+    service: &Service,
+    settings: Settings,
+    database_url: &str,
+    reload: impl Fn() -> anyhow::Result<Settings>,
+) -> anyhow::Result<()> {
+    let conn = db::connect(database_url).await?;
+    let state = Arc::new(State::new(conn, database_url, settings).await?);
+    let notifier = systemd::Notifier::from_env();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    // Once the signals have asked for a shutdown, the server still finishes its requests.
+    tokio::select! {
+        result = run(service, &state, &notifier, shutdown_rx) => result,
+        Err(err) = signals(&state, reload, &notifier, shutdown_tx) => Err(err),
+    }
+}
+
+/// Serve until shutdown turns true, then finish the requests and the heartbeat in flight.
+async fn run(
+    service: &Service,
+    state: &Arc<State>,
+    notifier: &systemd::Notifier,
+    shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let heartbeat_conn = match service.heartbeat_interval {
+        Some(_) => Some(db::connect(&state.database_url).await?),
+        None => None,
+    };
+    let make_service = {
+        let state = state.clone();
+        make_service_fn(move |_| {
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        })
+    };
+    let server = hyper::Server::try_bind(&service.listen)?.serve(make_service);
+    let listen = server.local_addr();
+    let server = server.with_graceful_shutdown(stopped(shutdown.clone()));
+    info!(%listen, "Serving");
+    notifier.notify(&format!("READY=1\nSTATUS=Serving on {}", listen));
+
+    let (served, (), ()) = tokio::join!(
+        server,
+        heartbeat_loop(
+            service.heartbeat_interval,
+            heartbeat_conn,
+            state,
+            shutdown.clone()
+        ),
+        watchdog_loop(service.heartbeat_interval, state, notifier, shutdown),
+    );
+    served?;
+    info!("Stopped");
+    Ok(())
+}
+
+async fn stopped(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Wait for SIGTERM or SIGINT and reload on SIGHUP until then. A failed reload is logged
+/// and the previous settings stay.
+async fn signals(
+    state: &State,
+    reload: impl Fn() -> anyhow::Result<Settings>,
+    notifier: &systemd::Notifier,
+    shutdown: watch::Sender<bool>,
+) -> anyhow::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            _ = sighup.recv() => {
+                notifier.notify("RELOADING=1");
+                match reload() {
+                    Ok(settings) => {
+                        *state.settings.write().unwrap() = settings;
+                        info!("Reloaded settings");
+                    }
+                    Err(err) => error!("Reload failed, keeping the settings: {:#}", err),
+                }
+                notifier.notify("READY=1");
+            }
+        }
+    }
+    info!("Stopping after requests in flight");
+    notifier.notify("STOPPING=1");
+    // The server only goes away when it has already returned.
+    let _ = shutdown.send(true);
+    Ok(())
+}
+
+/// Post heartbeats on an interval. A failed heartbeat is logged and retried on the next
+/// tick. A heartbeat in flight at shutdown finishes first.
+async fn heartbeat_loop(
+    interval: Option<u64>,
+    conn: Option<SqliteConnection>,
+    state: &State,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (interval, mut conn) = match (interval, conn) {
+        (Some(interval), Some(conn)) => (interval, conn),
+        _ => return,
+    };
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stopped(shutdown.clone()) => return,
+        }
+        let running = state.heartbeat.lock().await;
+        if let Err(err) = state.sync(&mut conn).await {
+            error!("Heartbeat failed: {:#}", err);
+        }
+        drop(running);
+        *state.heartbeat_at.lock().unwrap() = Instant::now();
+        if *shutdown.borrow_and_update() {
+            return;
+        }
+    }
+}
+
+/// Ping the systemd watchdog while the decision path gets the database connection and
+/// answers, and the heartbeat loop, if any, keeps finishing heartbeats. A hung service
+/// stops pinging and systemd restarts it.
+async fn watchdog_loop(
+    heartbeat_interval: Option<u64>,
+    state: &State,
+    notifier: &systemd::Notifier,
+    shutdown: watch::Receiver<bool>,
+) {
+    let interval = match systemd::watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = stopped(shutdown.clone()) => return,
+        }
+        let decisions = tokio::time::timeout(interval, async {
+            let mut conn = state.conn.lock().await;
+            sqlx::query("select 1").execute(&mut *conn).await
+        })
+        .await;
+        if !matches!(decisions, Ok(Ok(_))) {
+            warn!("Decision path is stuck, not pinging the watchdog");
+            continue;
+        }
+        if let Some(heartbeat_interval) = heartbeat_interval {
+            // A heartbeat may take its timeout, and prune and vacuum after it.
+            let limit =
+                Duration::from_secs(heartbeat_interval + state.settings().cloud.timeout) * 2;
+            if state.heartbeat_at.lock().unwrap().elapsed() > limit.max(interval) {
+                warn!("Heartbeat loop is stuck, not pinging the watchdog");
+                continue;
+            }
+        }
+        notifier.notify("WATCHDOG=1");
+    }
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path();
+    if path == "/access" || path.starts_with("/api/") {
+        let result = match path {
+            "/access" => reader::route(&state, req).await,
+            _ => api::route(&state, req).await,
+        };
+        return Ok(result.unwrap_or_else(|err| {
+            if err.status.is_server_error() {
+                error!("Request failed: {}", err.message);
+            }
+            err.into_response()
+        }));
+    }
+    let result = route(&state, req).await;
+    Ok(result.unwrap_or_else(|err| {
+        error!("Request failed: {:#}", err);
+        response(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+    }))
+}
+
+async fn route(state: &State, req: Request<Body>) -> anyhow::Result<Response<Body>> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut conn = state.conn.lock().await;
+            let mut tail = state.tail.lock().await;
+            let text = metrics::gather(&mut tail, &mut conn).await?;
+            Ok(Response::builder()
+                .header(hyper::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+                .body(Body::from(text))?)
+        }
+        _ => Ok(response(StatusCode::NOT_FOUND, "Not found".into())),
+    }
+}
+
+fn response(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_run() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let dir = std::env::temp_dir().join(format!("ahub-serve-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("notify");
+    let _ = std::fs::remove_file(&path);
+    let socket = tokio::net::UnixDatagram::bind(&path).unwrap();
+    let notifier = systemd::Notifier {
+        socket: Some(path.to_str().unwrap().to_string()),
+    };
+    let service = Service {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        heartbeat_interval: None,
+        admin_token: None,
+        deny_untrusted_clock: false,
+    };
+    let settings = Settings::new(
+        &service,
+        &heartbeat::Cloud {
+            access_api_url: None,
+            max_clock_skew: 30,
+            timeout: 30,
+            batch_size: None,
+        },
+        &Default::default(),
+    );
+    let state = Arc::new(
+        State::new(crate::db::test_conn().await, "sqlite::memory:", settings)
+            .await
+            .unwrap(),
+    );
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let client = async {
+        let mut buffer = [0u8; 128];
+        let n = socket.recv(&mut buffer).await.unwrap();
+        let ready = std::str::from_utf8(&buffer[..n]).unwrap();
+        let listen = ready.strip_prefix("READY=1\nSTATUS=Serving on ").unwrap();
+
+        // The request waits for the connection, which is held across the shutdown.
+        let conn = state.conn.lock().await;
+        let mut stream = tokio::net::TcpStream::connect(listen).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: hub\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(true).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(conn);
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+    let (result, response) = tokio::join!(run(&service, &state, &notifier, shutdown_rx), client);
+    result.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,540 @@
+use crate::db;
+use crate::domain::{Event, Hub, Point, Point2Reader, Point2User};
+use crate::json::{json_naive_date_time, json_option_naive_date_time};
+use crate::outbox;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, SqliteConnection};
+use std::collections::{HashMap, HashSet};
+
+/// Version of the snapshot document. Bump when its shape changes.
+pub const VERSION: i64 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub version: i64,
+    #[serde(with = "json_naive_date_time")]
+    pub exported_at: chrono::NaiveDateTime,
+    pub hub: Hub,
+    pub points: Vec<Point>,
+    pub users: Vec<SnapshotUser>,
+    pub assignments: Vec<Point2User>,
+    pub readers: Vec<SnapshotReader>,
+    pub reader_assignments: Vec<Point2Reader>,
+    pub events: Vec<Event>,
+}
+
+/// User with whether it was added on the hub rather than by the cloud.
+#[derive(PartialEq, Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotUser {
+    pub id: i64,
+    pub code: String,
+    #[serde(with = "json_option_naive_date_time")]
+    pub activate_code_at: Option<chrono::NaiveDateTime>,
+    #[serde(with = "json_option_naive_date_time")]
+    pub expire_code_at: Option<chrono::NaiveDateTime>,
+    pub local: bool,
+}
+
+/// Reader with the hash of its secret, so restored readers keep working.
+#[derive(PartialEq, Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotReader {
+    pub id: i64,
+    pub name: String,
+    pub secret_hash: String,
+    #[serde(with = "json_option_naive_date_time")]
+    pub last_seen_at: Option<chrono::NaiveDateTime>,
+}
+
+/// Write the hub, points, users, readers, their assignments and events to one JSON document.
+/// The api token is redacted like in all output, so the file does not carry the credential.
+pub async fn export(file: &str, database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::connect(database_url).await?;
+    let snapshot = read_snapshot(&mut conn).await?;
+    std::fs::write(file, serde_json::to_string_pretty(&snapshot)?)?;
+    println!(
+        "Exported {} points, {} users, {} assignments, {} readers and {} events to {}",
+        snapshot.points.len(),
+        snapshot.users.len(),
+        snapshot.assignments.len(),
+        snapshot.readers.len(),
+        snapshot.events.len(),
+        file
+    );
+    Ok(())
+}
+
+/// Restore a snapshot into a database without users, readers or events. The seeded hub and points
+/// are replaced. The hub has no api token until it is set with `ahub token --set`.
+pub async fn import(file: &str, database_url: &str) -> anyhow::Result<()> {
+    let snapshot = parse_snapshot(&std::fs::read_to_string(file)?)?;
+    let mut conn = db::connect(database_url).await?;
+    write_snapshot(&snapshot, &mut conn).await?;
+    println!(
+        "Imported {} points, {} users, {} assignments, {} readers and {} events from {}",
+        snapshot.points.len(),
+        snapshot.users.len(),
+        snapshot.assignments.len(),
+        snapshot.readers.len(),
+        snapshot.events.len(),
+        file
+    );
+    println!("Set the api token with `ahub token --set <token>` before the next heartbeat");
+    Ok(())
+}
+
+async fn read_snapshot(conn: &mut SqliteConnection) -> anyhow::Result<Snapshot> {
+    let hub: Hub =
+        sqlx::query_as("select id, api_token, cloud_last_access_event_at, clock_skew_seconds, clock_skew_exceeded from AccessHub")
+            .fetch_one(&mut *conn)
+            .await?;
+    let points: Vec<Point> =
+        sqlx::query_as(r#"select id, position from AccessPoint order by id asc"#)
+            .fetch_all(&mut *conn)
+            .await?;
+    let users: Vec<SnapshotUser> = sqlx::query_as(
+        r#"select id, code, activate_code_at, expire_code_at, local from AccessUser order by id asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let assignments: Vec<Point2User> = sqlx::query_as(
+        r#"select access_point_id, access_user_id from AccessPointToAccessUser
+        order by access_point_id asc, access_user_id asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let readers: Vec<SnapshotReader> = sqlx::query_as(
+        r#"select id, name, secret_hash, last_seen_at from AccessReader order by id asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let reader_assignments: Vec<Point2Reader> = sqlx::query_as(
+        r#"select access_point_id, access_reader_id from AccessPointToAccessReader
+        order by access_point_id asc, access_reader_id asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let events: Vec<Event> = sqlx::query_as(
+        r#"select id, at, access, code, access_user_id, access_point_id, reason, access_reader_id from AccessEvent
+        order by id asc"#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Snapshot {
+        version: VERSION,
+        exported_at: chrono::Utc::now().naive_utc(),
+        hub,
+        points,
+        users,
+        assignments,
+        readers,
+        reader_assignments,
+        events,
+    })
+}
+
+/// Check the version before the shape so a newer snapshot is reported as such instead of
+/// as a missing or unknown field.
+fn parse_snapshot(s: &str) -> anyhow::Result<Snapshot> {
+    let value: serde_json::Value = serde_json::from_str(s)?;
+    match value.get("version").and_then(|v| v.as_i64()) {
+        Some(VERSION) => {}
+        Some(version) => {
+            return Err(anyhow::anyhow!(
+                "Unsupported snapshot version {}, expected {}",
+                version,
+                VERSION
+            ))
+        }
+        None => return Err(anyhow::anyhow!("Missing snapshot version")),
+    }
+    serde_path_to_error::deserialize(value)
+        .map_err(|e| anyhow::anyhow!("Invalid snapshot at {}: {}", e.path(), e.inner()))
+}
+
+async fn write_snapshot(snapshot: &Snapshot, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+    let problems = validate_snapshot(snapshot);
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Invalid snapshot with {} problem(s):\n{}",
+            problems.len(),
+            problems
+                .iter()
+                .map(|p| format!("  {}", p))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    let mut tx = conn.begin().await?;
+    let (users, assignments, readers, events): (i64, i64, i64, i64) = sqlx::query_as(
+        r#"select (select count(*) from AccessUser), (select count(*) from AccessPointToAccessUser),
+        (select count(*) from AccessReader), (select count(*) from AccessEvent)"#,
+    )
+    .fetch_one(&mut tx)
+    .await?;
+    if users + assignments + readers + events != 0 {
+        return Err(anyhow::anyhow!(
+            "Database is not empty: {} users, {} assignments, {} readers and {} events",
+            users,
+            assignments,
+            readers,
+            events
+        ));
+    }
+
+    sqlx::query(r#"delete from AccessHub; delete from AccessPoint;"#)
+        .execute(&mut tx)
+        .await?;
+    let hub = &snapshot.hub;
+    sqlx::query(
+        r#"insert into AccessHub (id, api_token, cloud_last_access_event_at, clock_skew_seconds, clock_skew_exceeded)
+        values (?, '', ?, ?, ?)"#,
+    )
+    .bind(&hub.id)
+    .bind(hub.cloud_last_access_event_at)
+    .bind(hub.clock_skew_seconds)
+    .bind(hub.clock_skew_exceeded)
+    .execute(&mut tx)
+    .await?;
+    for p in snapshot.points.iter() {
+        sqlx::query(r#"insert into AccessPoint (id, position) values (?, ?)"#)
+            .bind(p.id)
+            .bind(p.position)
+            .execute(&mut tx)
+            .await?;
+    }
+    for u in snapshot.users.iter() {
+        sqlx::query(
+            r#"insert into AccessUser (id, code, activate_code_at, expire_code_at, local) values (?, ?, ?, ?, ?)"#,
+        )
+        .bind(u.id)
+        .bind(&u.code)
+        .bind(u.activate_code_at)
+        .bind(u.expire_code_at)
+        .bind(u.local)
+        .execute(&mut tx)
+        .await?;
+    }
+    for a in snapshot.assignments.iter() {
+        sqlx::query(
+            r#"insert into AccessPointToAccessUser (access_point_id, access_user_id) values (?, ?)"#,
+        )
+        .bind(a.access_point_id)
+        .bind(a.access_user_id)
+        .execute(&mut tx)
+        .await?;
+    }
+    for r in snapshot.readers.iter() {
+        sqlx::query(
+            r#"insert into AccessReader (id, name, secret_hash, last_seen_at) values (?, ?, ?, ?)"#,
+        )
+        .bind(r.id)
+        .bind(&r.name)
+        .bind(&r.secret_hash)
+        .bind(r.last_seen_at)
+        .execute(&mut tx)
+        .await?;
+    }
+    for a in snapshot.reader_assignments.iter() {
+        sqlx::query(
+            r#"insert into AccessPointToAccessReader (access_point_id, access_reader_id) values (?, ?)"#,
+        )
+        .bind(a.access_point_id)
+        .bind(a.access_reader_id)
+        .execute(&mut tx)
+        .await?;
+    }
+    for e in snapshot.events.iter() {
+        sqlx::query(
+            r#"insert into AccessEvent (id, at, access, code, access_user_id, access_point_id, reason, access_reader_id)
+            values (?, ?, ?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(e.id)
+        .bind(e.at)
+        .bind(&e.access)
+        .bind(&e.code)
+        .bind(e.access_user_id)
+        .bind(e.access_point_id)
+        .bind(&e.reason)
+        .bind(e.access_reader_id)
+        .execute(&mut tx)
+        .await?;
+    }
+    // Reader ids are not reused, and events may name readers removed before the export.
+    sqlx::query(
+        r#"insert into sqlite_sequence (name, seq) select 'AccessReader', 0
+        where not exists (select 1 from sqlite_sequence where name = 'AccessReader');
+        update sqlite_sequence set seq = max(seq, coalesce((select max(access_reader_id) from AccessEvent), 0))
+        where name = 'AccessReader';"#,
+    )
+    .execute(&mut tx)
+    .await?;
+    // The outbox trigger queued every event. Those the cloud cursor covers were uploaded.
+    if let Some(cloud_last_access_event_at) = hub.cloud_last_access_event_at {
+        outbox::acknowledge_events(cloud_last_access_event_at, &mut tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct SnapshotProblem {
+    path: String,
+    message: String,
+}
+
+impl std::fmt::Display for SnapshotProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Collect every problem so an import is rejected as a whole before anything is written.
+fn validate_snapshot(snapshot: &Snapshot) -> Vec<SnapshotProblem> {
+    let mut problems = Vec::<SnapshotProblem>::new();
+    let mut problem =
+        |path: String, message: String| problems.push(SnapshotProblem { path, message });
+
+    let mut point_ids = HashMap::<i64, usize>::new();
+    let mut positions = HashMap::<i64, usize>::new();
+    for (i, p) in snapshot.points.iter().enumerate() {
+        let first = *point_ids.entry(p.id).or_insert(i);
+        if first != i {
+            problem(
+                format!("points[{}].id", i),
+                format!("duplicate point id {}, first at points[{}]", p.id, first),
+            );
+        }
+        let first = *positions.entry(p.position).or_insert(i);
+        if first != i {
+            problem(
+                format!("points[{}].position", i),
+                format!(
+                    "duplicate position {}, first at points[{}]",
+                    p.position, first
+                ),
+            );
+        }
+    }
+
+    let mut user_ids = HashMap::<i64, usize>::new();
+    let mut codes = HashMap::<&str, usize>::new();
+    for (i, u) in snapshot.users.iter().enumerate() {
+        let first = *user_ids.entry(u.id).or_insert(i);
+        if first != i {
+            problem(
+                format!("users[{}].id", i),
+                format!("duplicate user id {}, first at users[{}]", u.id, first),
+            );
+        }
+        let first = *codes.entry(&u.code).or_insert(i);
+        if first != i {
+            problem(
+                format!("users[{}].code", i),
+                format!("duplicate code {}, first at users[{}]", u.code, first),
+            );
+        }
+        match (u.local, u.id < 0) {
+            (true, false) => problem(
+                format!("users[{}].local", i),
+                format!("user {} is local but local user ids are negative", u.id),
+            ),
+            (false, true) => problem(
+                format!("users[{}].local", i),
+                format!("user {} is negative but not local", u.id),
+            ),
+            _ => {}
+        }
+    }
+
+    let mut assignments = HashSet::<(i64, i64)>::new();
+    for (i, a) in snapshot.assignments.iter().enumerate() {
+        let path = format!("assignments[{}]", i);
+        if !point_ids.contains_key(&a.access_point_id) {
+            problem(
+                format!("{}.accessPointId", path),
+                format!("unknown point id {}", a.access_point_id),
+            );
+        }
+        if !user_ids.contains_key(&a.access_user_id) {
+            problem(
+                format!("{}.accessUserId", path),
+                format!("unknown user id {}", a.access_user_id),
+            );
+        }
+        if !assignments.insert((a.access_point_id, a.access_user_id)) {
+            problem(
+                path,
+                format!(
+                    "duplicate assignment of user {} to point {}",
+                    a.access_user_id, a.access_point_id
+                ),
+            );
+        }
+    }
+
+    let mut reader_ids = HashMap::<i64, usize>::new();
+    let mut names = HashMap::<&str, usize>::new();
+    let mut secret_hashes = HashMap::<&str, usize>::new();
+    for (i, r) in snapshot.readers.iter().enumerate() {
+        let first = *reader_ids.entry(r.id).or_insert(i);
+        if first != i {
+            problem(
+                format!("readers[{}].id", i),
+                format!("duplicate reader id {}, first at readers[{}]", r.id, first),
+            );
+        }
+        let first = *names.entry(&r.name).or_insert(i);
+        if first != i {
+            problem(
+                format!("readers[{}].name", i),
+                format!("duplicate name {}, first at readers[{}]", r.name, first),
+            );
+        }
+        let first = *secret_hashes.entry(&r.secret_hash).or_insert(i);
+        if first != i {
+            problem(
+                format!("readers[{}].secretHash", i),
+                format!("duplicate secret hash, first at readers[{}]", first),
+            );
+        }
+    }
+
+    let mut reader_assignments = HashSet::<(i64, i64)>::new();
+    for (i, a) in snapshot.reader_assignments.iter().enumerate() {
+        let path = format!("readerAssignments[{}]", i);
+        if !point_ids.contains_key(&a.access_point_id) {
+            problem(
+                format!("{}.accessPointId", path),
+                format!("unknown point id {}", a.access_point_id),
+            );
+        }
+        if !reader_ids.contains_key(&a.access_reader_id) {
+            problem(
+                format!("{}.accessReaderId", path),
+                format!("unknown reader id {}", a.access_reader_id),
+            );
+        }
+        if !reader_assignments.insert((a.access_point_id, a.access_reader_id)) {
+            problem(
+                path,
+                format!(
+                    "duplicate assignment of reader {} to point {}",
+                    a.access_reader_id, a.access_point_id
+                ),
+            );
+        }
+    }
+
+    // Event users may have been deleted since, so only the point is checked.
+    let mut event_ids = HashMap::<i64, usize>::new();
+    for (i, e) in snapshot.events.iter().enumerate() {
+        let path = format!("events[{}]", i);
+        let first = *event_ids.entry(e.id).or_insert(i);
+        if first != i {
+            problem(
+                format!("{}.id", path),
+                format!("duplicate event id {}, first at events[{}]", e.id, first),
+            );
+        }
+        if e.access != "grant" && e.access != "deny" {
+            problem(
+                format!("{}.access", path),
+                format!("access {} is not grant or deny", e.access),
+            );
+        }
+        if !point_ids.contains_key(&e.access_point_id) {
+            problem(
+                format!("{}.accessPointId", path),
+                format!("unknown point id {}", e.access_point_id),
+            );
+        }
+    }
+    problems
+}
+
+#[tokio::test]
+async fn test_snapshot_roundtrip() {
+    let mut conn = crate::db::test_conn().await;
+    sqlx::query(
+        r#"update AccessHub set cloud_last_access_event_at = '2022-04-01 00:00:00', api_token = 'hub-secret';
+        insert into AccessUser (id, code, expire_code_at, local) values (10, '111', '2030-01-01 00:00:00', false), (-1, '222', null, true);
+        insert into AccessPointToAccessUser (access_point_id, access_user_id) values (1, 10), (2, -1);
+        insert into AccessReader (id, name, secret_hash) values (1, 'front-door', 'abc');
+        insert into AccessPointToAccessReader (access_point_id, access_reader_id) values (1, 1);
+        insert into AccessEvent (id, at, access, code, access_user_id, access_point_id, reason, access_reader_id)
+        values (1, '2022-03-31 12:00:00', 'grant', '111', 10, 1, null, 1),
+          (2, '2022-04-02 12:00:00', 'deny', '999', null, 2, 'code', 5);"#,
+    )
+    .execute(&mut conn)
+    .await
+    .unwrap();
+    let json = serde_json::to_string(&read_snapshot(&mut conn).await.unwrap()).unwrap();
+    assert!(!json.contains("hub-secret"));
+
+    let snapshot = parse_snapshot(&json).unwrap();
+    let mut restored = crate::db::test_conn().await;
+    write_snapshot(&snapshot, &mut restored).await.unwrap();
+    let again = read_snapshot(&mut restored).await.unwrap();
+    let (api_token,): (String,) = sqlx::query_as(r#"select api_token from AccessHub"#)
+        .fetch_one(&mut restored)
+        .await
+        .unwrap();
+    assert_eq!(api_token, "");
+    assert_eq!(again.users, snapshot.users);
+    assert!(again.users[0].local);
+    assert_eq!(again.readers, snapshot.readers);
+    assert_eq!(again.reader_assignments.len(), 1);
+    assert_eq!(again.events.len(), 2);
+    assert_eq!(again.assignments.len(), 2);
+    // The removed reader 5 of the last event keeps its id.
+    let (id, _) = crate::admin::add_reader(None, "back-door", None, &[2], "test", &mut restored)
+        .await
+        .unwrap();
+    assert_eq!(id, 6);
+
+    let pending: Vec<(i64,)> = sqlx::query_as(
+        r#"select record_id from Outbox where kind = 'event' and delivered_at is null"#,
+    )
+    .fetch_all(&mut restored)
+    .await
+    .unwrap();
+    assert_eq!(pending, vec![(2,)]);
+
+    assert!(write_snapshot(&snapshot, &mut restored).await.is_err());
+}
+
+#[test]
+fn test_validate_snapshot() {
+    let json = r#"{"version":2,"exportedAt":"2022-04-01T00:00:00.000Z",
+        "hub":{"id":"h","apiToken":"","cloudLastAccessEventAt":null,"clockSkewSeconds":null,"clockSkewExceeded":false},
+        "points":[{"id":1,"position":1},{"id":2,"position":1}],
+        "users":[{"id":10,"code":"111","activateCodeAt":null,"expireCodeAt":null,"local":false},
+          {"id":11,"code":"111","activateCodeAt":null,"expireCodeAt":null,"local":true}],
+        "assignments":[{"accessPointId":3,"accessUserId":10}],
+        "readers":[{"id":1,"name":"door","secretHash":"abc","lastSeenAt":null},
+          {"id":2,"name":"door","secretHash":"abc","lastSeenAt":null}],
+        "readerAssignments":[{"accessPointId":1,"accessReaderId":3}],
+        "events":[{"id":1,"at":"2022-04-01T00:00:00.000Z","access":"maybe","code":"111","accessUserId":null,"accessPointId":1,"reason":null}]}"#;
+    let problems: Vec<String> = validate_snapshot(&parse_snapshot(json).unwrap())
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    assert_eq!(
+        problems,
+        vec![
+            "points[1].position: duplicate position 1, first at points[0]",
+            "users[1].code: duplicate code 111, first at users[0]",
+            "users[1].local: user 11 is local but local user ids are negative",
+            "assignments[0].accessPointId: unknown point id 3",
+            "readers[1].name: duplicate name door, first at readers[0]",
+            "readers[1].secretHash: duplicate secret hash, first at readers[0]",
+            "readerAssignments[0].accessReaderId: unknown reader id 3",
+            "events[0].access: access maybe is not grant or deny",
+        ]
+    );
+    assert!(parse_snapshot(&json.replace(r#""version":2"#, r#""version":3"#)).is_err());
+}
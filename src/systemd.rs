@@ -0,0 +1,80 @@
+use std::time::Duration;
+use tracing::warn;
+
+/// Socket of the service manager, from NOTIFY_SOCKET. Without one, as when not run by
+/// systemd with `Type=notify`, nothing is sent.
+#[derive(Debug)]
+pub struct Notifier {
+    pub socket: Option<String>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        Self {
+            socket: std::env::var("NOTIFY_SOCKET").ok(),
+        }
+    }
+
+    /// Send a state such as `READY=1` or `WATCHDOG=1`. A failure is logged, since the
+    /// service keeps working when the manager does not hear from it.
+    pub fn notify(&self, state: &str) {
+        if let Some(path) = &self.socket {
+            if let Err(err) = notify_to(path, state) {
+                warn!("Notify {} of {:?} failed: {}", path, state, err);
+            }
+        }
+    }
+}
+
+/// Send state to the datagram socket at path. A path starting with @ is in the abstract
+/// namespace.
+#[cfg(unix)]
+fn notify_to(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_to(_path: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// How often to ping the watchdog: half of WATCHDOG_USEC, the margin systemd recommends.
+/// None without a watchdog or when it watches another process.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match usec {
+        0 => None,
+        usec => Some(Duration::from_micros(usec / 2)),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_notify_to() {
+    let dir = std::env::temp_dir().join(format!("ahub-notify-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("notify");
+    let _ = std::fs::remove_file(&path);
+    let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+    notify_to(path.to_str().unwrap(), "READY=1\nSTATUS=Serving").unwrap();
+    let mut buffer = [0u8; 64];
+    let n = socket.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..n], b"READY=1\nSTATUS=Serving");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
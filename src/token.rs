@@ -1,16 +1,41 @@
+use crate::db;
 use crate::domain::Hub;
-// use anyhow::Context;
-use sqlx::{Connection, SqliteConnection};
 
-pub async fn token(set: &str, database_url: &str) -> anyhow::Result<()> {
-    let mut conn = SqliteConnection::connect(database_url).await?;
+/// Print the api token or set it. A new database has no hub until hub_id and a token
+/// provision it with the id and token the cloud assigned to this hub.
+pub async fn token(set: &str, hub_id: Option<&str>, database_url: &str) -> anyhow::Result<()> {
+    let mut conn = db::connect(database_url).await?;
 
-    let hub: Hub =
-        sqlx::query_as("select id, api_token, cloud_last_access_event_at from AccessHub")
-            .fetch_one(&mut conn)
+    let hub: Option<Hub> =
+        sqlx::query_as("select id, api_token, cloud_last_access_event_at, clock_skew_seconds, clock_skew_exceeded from AccessHub")
+            .fetch_optional(&mut conn)
             .await?;
+    let hub = match (hub, hub_id) {
+        (None, Some(hub_id)) => {
+            if hub_id.is_empty() || set.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Provisioning needs a hub id and --set token"
+                ));
+            }
+            sqlx::query("insert into AccessHub (id, api_token) values (?, ?)")
+                .bind(hub_id)
+                .bind(set)
+                .execute(&mut conn)
+                .await?;
+            println!("Provisioned hub {}", hub_id);
+            return Ok(());
+        }
+        (None, None) => {
+            return Err(anyhow::anyhow!(
+                "Hub is not provisioned: run `ahub token --hub-id <id> --set <token>`"
+            ))
+        }
+        (Some(hub), Some(hub_id)) if hub.id != hub_id => {
+            return Err(anyhow::anyhow!("Hub is already provisioned as {}", hub.id))
+        }
+        (Some(hub), _) => hub,
+    };
     if set.is_empty() {
-        // println!("{:#?}", hub);
         println!("token: {}", hub.api_token)
     } else {
         let rows_affected = sqlx::query("update AccessHub set api_token=? where id=?")